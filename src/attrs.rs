@@ -0,0 +1,203 @@
+use crate::display::quote_path;
+use crate::error::AppError;
+use serde::{Deserialize, Serialize};
+use std::{
+    ffi::CString,
+    io,
+    os::unix::{ffi::OsStrExt, fs::MetadataExt},
+    path::Path,
+};
+
+/// A point in time with nanosecond precision, as kept by the file system
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, Default, PartialEq)]
+pub struct Stamp {
+    pub sec: i64,
+    pub nsec: i64,
+}
+
+/// An extended attribute. POSIX ACLs live in `system.posix_acl_access` and
+/// `system.posix_acl_default`, so they travel with the other attributes.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct Xattr {
+    pub name: String,
+    /// the raw value in hex
+    pub value: String,
+}
+
+/// Everything besides the mode that is put back on an item when it is restored
+#[derive(Deserialize, Serialize, Debug, Clone, Default, PartialEq)]
+pub struct Attrs {
+    pub uid: u32,
+    pub gid: u32,
+    pub atime: Stamp,
+    pub mtime: Stamp,
+    #[serde(default)]
+    pub xattrs: Vec<Xattr>,
+}
+
+fn c_path(path: &Path) -> Result<CString, AppError> {
+    CString::new(path.as_os_str().as_bytes()).map_err(|_| AppError {
+        code: -17,
+        message: format!("{} contains a NUL byte", quote_path(path)),
+    })
+}
+
+/// Read the attributes of `path` without following a symlink
+pub fn capture(path: &Path) -> Result<Attrs, AppError> {
+    let md = path.symlink_metadata()?;
+    Ok(Attrs {
+        uid: md.uid(),
+        gid: md.gid(),
+        atime: Stamp {
+            sec: md.atime(),
+            nsec: md.atime_nsec(),
+        },
+        mtime: Stamp {
+            sec: md.mtime(),
+            nsec: md.mtime_nsec(),
+        },
+        xattrs: list_xattrs(path)?,
+    })
+}
+
+/// Put `attrs` and `mode` back on `path`. Owner and extended attributes
+/// usually need privileges the user lacks; such failures don't stop the
+/// restore and are returned as warnings.
+pub fn apply(path: &Path, attrs: &Attrs, mode: u32) -> Result<Vec<String>, AppError> {
+    let c = c_path(path)?;
+    let mut warnings: Vec<String> = Vec::new();
+    let md = path.symlink_metadata()?;
+    let is_symlink = md.file_type().is_symlink();
+    // chown clears set-id bits, so it has to come before chmod
+    if (md.uid(), md.gid()) != (attrs.uid, attrs.gid) {
+        let ret = unsafe { libc::lchown(c.as_ptr(), attrs.uid, attrs.gid) };
+        if ret != 0 {
+            warnings.push(format!(
+                "could not restore owner {}:{}: {}",
+                attrs.uid,
+                attrs.gid,
+                io::Error::last_os_error()
+            ));
+        }
+    }
+    // only the owner may chmod, and the mode usually survived the trash anyway
+    if !is_symlink && path.symlink_metadata()?.mode() & 0o7777 != mode & 0o7777 {
+        let ret = unsafe { libc::chmod(c.as_ptr(), (mode & 0o7777) as libc::mode_t) };
+        if ret != 0 {
+            warnings.push(format!(
+                "could not restore mode {:03o}: {}",
+                mode & 0o7777,
+                io::Error::last_os_error()
+            ));
+        }
+    }
+    for xattr in &attrs.xattrs {
+        if let Err(e) = set_xattr(&c, xattr) {
+            warnings.push(format!("could not restore attribute {}: {}", xattr.name, e));
+        }
+    }
+    // timestamps go last so that nothing above can bump them
+    let times = [
+        libc::timespec {
+            tv_sec: attrs.atime.sec as libc::time_t,
+            tv_nsec: attrs.atime.nsec as _,
+        },
+        libc::timespec {
+            tv_sec: attrs.mtime.sec as libc::time_t,
+            tv_nsec: attrs.mtime.nsec as _,
+        },
+    ];
+    let ret = unsafe {
+        libc::utimensat(
+            libc::AT_FDCWD,
+            c.as_ptr(),
+            times.as_ptr(),
+            libc::AT_SYMLINK_NOFOLLOW,
+        )
+    };
+    if ret != 0 {
+        warnings.push(format!(
+            "could not restore timestamps: {}",
+            io::Error::last_os_error()
+        ));
+    }
+    Ok(warnings)
+}
+
+#[cfg(target_os = "linux")]
+fn list_xattrs(path: &Path) -> Result<Vec<Xattr>, AppError> {
+    let c = c_path(path)?;
+    let size = unsafe { libc::llistxattr(c.as_ptr(), std::ptr::null_mut(), 0) };
+    if size < 0 {
+        let e = io::Error::last_os_error();
+        // file systems without xattr support have nothing to preserve
+        if e.raw_os_error() == Some(libc::ENOTSUP) {
+            return Ok(Vec::new());
+        }
+        return Err(e.into());
+    }
+    let mut names = vec![0_u8; size as usize];
+    let size = unsafe { libc::llistxattr(c.as_ptr(), names.as_mut_ptr() as *mut libc::c_char, names.len()) };
+    if size < 0 {
+        return Err(io::Error::last_os_error().into());
+    }
+    names.truncate(size as usize);
+    let mut xattrs = Vec::new();
+    for name in names.split(|b| *b == 0).filter(|n| !n.is_empty()) {
+        let c_name = CString::new(name).unwrap();
+        let len = unsafe { libc::lgetxattr(c.as_ptr(), c_name.as_ptr(), std::ptr::null_mut(), 0) };
+        if len < 0 {
+            continue;
+        }
+        let mut value = vec![0_u8; len as usize];
+        let len = unsafe {
+            libc::lgetxattr(
+                c.as_ptr(),
+                c_name.as_ptr(),
+                value.as_mut_ptr() as *mut libc::c_void,
+                value.len(),
+            )
+        };
+        if len < 0 {
+            continue;
+        }
+        value.truncate(len as usize);
+        xattrs.push(Xattr {
+            name: String::from_utf8_lossy(name).into_owned(),
+            value: value.iter().map(|b| format!("{:02x}", b)).collect(),
+        });
+    }
+    Ok(xattrs)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn list_xattrs(_path: &Path) -> Result<Vec<Xattr>, AppError> {
+    Ok(Vec::new())
+}
+
+#[cfg(target_os = "linux")]
+fn set_xattr(c: &CString, xattr: &Xattr) -> io::Result<()> {
+    let name = CString::new(xattr.name.as_bytes())?;
+    let value: Vec<u8> = (0..xattr.value.len())
+        .step_by(2)
+        .filter_map(|i| u8::from_str_radix(xattr.value.get(i..i + 2)?, 16).ok())
+        .collect();
+    let ret = unsafe {
+        libc::lsetxattr(
+            c.as_ptr(),
+            name.as_ptr(),
+            value.as_ptr() as *const libc::c_void,
+            value.len(),
+            0,
+        )
+    };
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_xattr(_c: &CString, _xattr: &Xattr) -> io::Result<()> {
+    Ok(())
+}
@@ -0,0 +1,312 @@
+use crate::display::{escape_path, quote, quote_path};
+use crate::error::AppError;
+use crate::info::{self, TrashInfo};
+use crate::{friendly_size, get_dir_size, get_type, type_char};
+use std::{
+    env,
+    ffi::{OsStr, OsString},
+    fs::{self, read_dir, File},
+    io::{self, stdout, IsTerminal, Read, Write},
+    os::unix::{ffi::OsStrExt, fs::PermissionsExt},
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+/// `rmrs -b`: every item in the trash can with its type, mode and size
+pub fn show_trash() -> Result<(), AppError> {
+    let trash_home = PathBuf::from(env::var("th").unwrap());
+    let trash_can = PathBuf::from(env::var("tc").unwrap());
+    let mut total_size: u64 = 0;
+    for record in info::all(&trash_home)? {
+        let pb = record.stored();
+        // a record without its item is reported by `rmrs doctor`
+        let Ok(md) = pb.symlink_metadata() else { continue };
+        let size = item_size(&pb, &md);
+        total_size += size;
+        println!(
+            "{} {} ${:03o} {} {}",
+            quote(OsStr::new(&record.id)),
+            type_char(&md.file_type()),
+            record.mode % 512,
+            display_name(&record.name, &pb, &md),
+            friendly_size(size)
+        );
+    }
+    // items trashed by older versions of rmrs, or cut off by a crash
+    for entry in read_dir(trash_can)? {
+        let pb = entry?.path();
+        let id = pb.file_name().unwrap().to_os_string();
+        if info::load(&trash_home, &id.to_string_lossy()).is_ok() {
+            continue;
+        }
+        let md = pb.symlink_metadata()?;
+        let size = item_size(&pb, &md);
+        total_size += size;
+        println!(
+            "{} {} ${:03o} {} {} (no record)",
+            quote(&id),
+            type_char(&md.file_type()),
+            md.permissions().mode() % 512,
+            display_name(&id, &pb, &md),
+            friendly_size(size)
+        );
+    }
+    println!("total {}", friendly_size(total_size));
+    Ok(())
+}
+
+/// `name`, and where it points to for a symlink
+pub fn display_name(name: &OsStr, pb: &Path, md: &fs::Metadata) -> String {
+    if md.file_type().is_symlink() {
+        if let Ok(target) = fs::read_link(pb) {
+            return format!("{} -> {}", quote(name), quote_path(&target));
+        }
+    }
+    quote(name)
+}
+
+/// Size of a file, or of everything inside a directory
+pub fn item_size(pb: &PathBuf, md: &fs::Metadata) -> u64 {
+    if md.is_dir() {
+        get_dir_size(pb).unwrap_or(0)
+    } else {
+        md.len()
+    }
+}
+
+/// One line of `ls` and `tree`: type, mode, name and size
+fn entry_line(name: &OsStr, pb: &PathBuf, mode: u32) -> Result<String, AppError> {
+    let md = pb.symlink_metadata()?;
+    Ok(format!(
+        "{} ${:03o} {} {}",
+        type_char(&md.file_type()),
+        mode % 512,
+        display_name(name, pb, &md),
+        friendly_size(item_size(pb, &md))
+    ))
+}
+
+/// Entries of a directory in the trash can, sorted by name
+fn entries(dir: &Path) -> Result<Vec<PathBuf>, AppError> {
+    let mut found: Vec<PathBuf> = Vec::new();
+    for entry in read_dir(dir)? {
+        found.push(entry?.path());
+    }
+    found.sort();
+    Ok(found)
+}
+
+/// The item of `record` or, with `sub`, something inside of it
+fn pick(record: &TrashInfo, sub: Option<&Path>) -> Result<PathBuf, AppError> {
+    match sub {
+        Some(sub) => record.locate(sub).map_err(|message| AppError { code: -16, message }),
+        None => Ok(record.stored()),
+    }
+}
+
+/// `rmrs ls`: what is inside a trashed directory, or a trashed file itself.
+/// Nothing in the trash can is touched, so modes are those at deletion.
+pub fn ls(record: &TrashInfo, sub: Option<&Path>) -> Result<(), AppError> {
+    let pb = pick(record, sub)?;
+    let md = pb.symlink_metadata()?;
+    if !md.is_dir() {
+        let name = pb.file_name().unwrap_or_default().to_os_string();
+        let name = if sub.is_some() { name } else { record.name.clone() };
+        println!("{}", entry_line(&name, &pb, md.permissions().mode())?);
+        return Ok(());
+    }
+    for child in entries(&pb)? {
+        let mode = child.symlink_metadata()?.permissions().mode();
+        println!("{}", entry_line(child.file_name().unwrap(), &child, mode)?);
+    }
+    Ok(())
+}
+
+/// `rmrs ls --deleted`: what is in `dir` now, and what used to be there and
+/// is in the trash, either as an item of its own or inside a trashed parent
+pub fn ls_deleted(dir: &Path) -> Result<(), AppError> {
+    let th = PathBuf::from(env::var("th").unwrap());
+    // (name, line) of every entry, to be sorted by name
+    let mut lines: Vec<(OsString, String)> = Vec::new();
+    if let Ok(live) = entries(dir) {
+        for pb in live {
+            let name = pb.file_name().unwrap().to_os_string();
+            let mode = pb.symlink_metadata()?.permissions().mode();
+            lines.push((name.clone(), entry_line(&name, &pb, mode)?));
+        }
+    }
+    for record in info::all(&th)? {
+        let deleted = |name: &OsStr, pb: &PathBuf, mode: u32| -> Result<(OsString, String), AppError> {
+            Ok((
+                name.to_os_string(),
+                format!(
+                    "{} (deleted {} by {} as {})",
+                    entry_line(name, pb, mode)?,
+                    record.deleted_time(),
+                    quote(OsStr::new(&record.user)),
+                    quote(OsStr::new(&record.id))
+                ),
+            ))
+        };
+        if record.path.parent() == Some(dir) && record.stored().symlink_metadata().is_ok() {
+            lines.push(deleted(&record.name, &record.stored(), record.mode)?);
+        }
+        // `dir` itself, or one of its parents, went into the trash with its content
+        let Ok(rel) = dir.strip_prefix(&record.path) else { continue };
+        let Ok(inside) = record.locate(rel) else { continue };
+        if !inside.symlink_metadata()?.is_dir() {
+            continue;
+        }
+        for pb in entries(&inside)? {
+            let mode = pb.symlink_metadata()?.permissions().mode();
+            lines.push(deleted(pb.file_name().unwrap(), &pb, mode)?);
+        }
+    }
+    if lines.is_empty() && dir.symlink_metadata().is_err() {
+        return Err(AppError {
+            code: -16,
+            message: format!("{} neither exists nor is in the trash", quote_path(dir)),
+        });
+    }
+    lines.sort_by(|a, b| a.0.cmp(&b.0));
+    for (_, line) in lines {
+        println!("{}", line);
+    }
+    Ok(())
+}
+
+/// `rmrs tree`: everything inside a trashed item, indented by depth
+pub fn tree(record: &TrashInfo, sub: Option<&Path>) -> Result<(), AppError> {
+    let pb = pick(record, sub)?;
+    let name = match sub {
+        Some(_) => pb.file_name().unwrap_or_default().to_os_string(),
+        None => record.name.clone(),
+    };
+    println!("{}", entry_line(&name, &pb, pb.symlink_metadata()?.permissions().mode())?);
+    if pb.symlink_metadata()?.is_dir() {
+        print_tree(&pb, "")?;
+    }
+    Ok(())
+}
+
+fn print_tree(dir: &Path, indent: &str) -> Result<(), AppError> {
+    let children = match entries(dir) {
+        Ok(children) => children,
+        Err(e) => {
+            println!("{}└── ({})", indent, e.message);
+            return Ok(());
+        }
+    };
+    for (i, child) in children.iter().enumerate() {
+        let is_last = i + 1 == children.len();
+        let md = child.symlink_metadata()?;
+        println!(
+            "{}{} {}",
+            indent,
+            if is_last { "└──" } else { "├──" },
+            entry_line(child.file_name().unwrap(), child, md.permissions().mode())?
+        );
+        if md.is_dir() {
+            print_tree(child, &format!("{}{}", indent, if is_last { "    " } else { "│   " }))?;
+        }
+    }
+    Ok(())
+}
+
+/// Split `<item>[/<subpath>]` into the item and the path inside it
+pub fn parse_spec(spec: &OsStr) -> (String, Option<PathBuf>) {
+    let bytes = spec.as_bytes();
+    match bytes.iter().position(|b| *b == b'/') {
+        Some(i) => (
+            String::from_utf8_lossy(&bytes[..i]).into_owned(),
+            Some(PathBuf::from(OsStr::from_bytes(&bytes[i + 1..]))).filter(|p| !p.as_os_str().is_empty()),
+        ),
+        None => (spec.to_string_lossy().into_owned(), None),
+    }
+}
+
+/// Whether the start of a file looks binary, i.e. contains a NUL byte
+pub fn is_binary(path: &Path) -> io::Result<bool> {
+    let mut head = Vec::with_capacity(8192);
+    File::open(path)?.take(8192).read_to_end(&mut head)?;
+    Ok(head.contains(&0))
+}
+
+/// `rmrs cat`: print a trashed file. Binary content is only written to a
+/// pipe or a file, never to the terminal.
+pub fn cat(record: &TrashInfo, sub: Option<&Path>) -> Result<(), AppError> {
+    let pb = pick(record, sub)?;
+    let md = pb.symlink_metadata()?;
+    let shown = sub.unwrap_or(Path::new(&record.name));
+    if !md.is_file() {
+        return Err(AppError {
+            code: -20,
+            message: format!("{} is a {}, not a file", quote_path(shown), get_type(&pb)),
+        });
+    }
+    if stdout().is_terminal() && is_binary(&pb)? {
+        return Err(AppError {
+            code: -20,
+            message: format!(
+                "{} is binary ({}), redirect the output to see it",
+                quote_path(shown),
+                friendly_size(md.len())
+            ),
+        });
+    }
+    let mut out = stdout().lock();
+    match io::copy(&mut File::open(&pb)?, &mut out) {
+        // `| head` closing the pipe early is fine
+        Err(e) if e.kind() == io::ErrorKind::BrokenPipe => Ok(()),
+        Err(e) => Err(e.into()),
+        Ok(_) => Ok(out.flush()?),
+    }
+}
+
+/// `rmrs diff`: compare a trashed file or directory with `other`, or with
+/// whatever is at its original location now, using `diff -u`
+pub fn diff(record: &TrashInfo, sub: Option<&Path>, other: Option<(PathBuf, String)>) -> Result<(), AppError> {
+    let pb = pick(record, sub)?;
+    let label = match sub {
+        Some(sub) => format!("{}/{}", record.id, escape_path(sub)),
+        None => record.id.clone(),
+    };
+    let (other, other_label) = other.unwrap_or_else(|| {
+        let original = match sub {
+            Some(sub) => record.path.join(sub),
+            None => record.path.clone(),
+        };
+        let label = escape_path(&original);
+        (original, label)
+    });
+    if other.symlink_metadata().is_err() {
+        return Err(AppError {
+            code: -1,
+            message: format!("{} doesn't exist", quote_path(&other)),
+        });
+    }
+    let mut args: Vec<OsString> = vec!["-u".into()];
+    // labels would stand in for every file of a recursive diff
+    if pb.symlink_metadata()?.is_dir() {
+        args.push("-r".into());
+    } else {
+        args.extend(["--label".into(), label.into(), "--label".into(), other_label.into()]);
+    }
+    let status = Command::new("diff")
+        .args(args)
+        .arg(&pb)
+        .arg(&other)
+        .status()
+        .map_err(|e| AppError {
+            code: -20,
+            message: format!("could not run diff: {}", e),
+        })?;
+    // 1 only means that there are differences
+    match status.code() {
+        Some(0) | Some(1) => Ok(()),
+        _ => Err(AppError {
+            code: -20,
+            message: format!("diff failed: {}", status),
+        }),
+    }
+}
@@ -0,0 +1,228 @@
+use std::{
+    env,
+    ffi::{OsStr, OsString},
+    os::unix::ffi::{OsStrExt, OsStringExt},
+    path::Path,
+};
+
+/// C-style escaping of a file name or path, safe to put between double
+/// quotes in the line-oriented log. Control characters, quotes and
+/// backslashes are escaped, bytes that aren't UTF-8 become `\xNN`, and
+/// [`unescape`] gets the exact bytes back.
+pub fn escape(s: &OsStr) -> String {
+    let mut out = String::new();
+    for chunk in s.as_bytes().utf8_chunks() {
+        for c in chunk.valid().chars() {
+            push_c_escaped(&mut out, c);
+        }
+        for b in chunk.invalid() {
+            out.push_str(&format!("\\x{:02x}", b));
+        }
+    }
+    out
+}
+
+pub fn escape_path(p: &Path) -> String {
+    escape(p.as_os_str())
+}
+
+fn push_c_escaped(out: &mut String, c: char) {
+    match c {
+        '\\' => out.push_str("\\\\"),
+        '"' => out.push_str("\\\""),
+        '\n' => out.push_str("\\n"),
+        '\r' => out.push_str("\\r"),
+        '\t' => out.push_str("\\t"),
+        c if (c as u32) < 0x80 && c.is_control() => out.push_str(&format!("\\x{:02x}", c as u32)),
+        c if c.is_control() || is_bidi_control(c) => out.push_str(&format!("\\u{{{:04x}}}", c as u32)),
+        c => out.push(c),
+    }
+}
+
+/// `text` with control characters escaped as in [`escape`], for printing
+/// content rather than names: quotes and backslashes stay as they are
+pub fn printable(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '\t' => out.push(c),
+            c if c.is_control() || is_bidi_control(c) => push_c_escaped(&mut out, c),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+/// Characters that reorder the text around them on screen
+fn is_bidi_control(c: char) -> bool {
+    matches!(c, '\u{200e}' | '\u{200f}' | '\u{202a}'..='\u{202e}' | '\u{2066}'..='\u{2069}')
+}
+
+/// Undo [`escape`]
+pub fn unescape(s: &str) -> OsString {
+    let bytes = s.as_bytes();
+    let mut out: Vec<u8> = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'\\' && i + 1 < bytes.len() {
+            let simple = match bytes[i + 1] {
+                b'\\' => Some(b'\\'),
+                b'"' => Some(b'"'),
+                b'n' => Some(b'\n'),
+                b'r' => Some(b'\r'),
+                b't' => Some(b'\t'),
+                _ => None,
+            };
+            if let Some(b) = simple {
+                out.push(b);
+                i += 2;
+                continue;
+            }
+            if bytes[i + 1] == b'x' && i + 3 < bytes.len() {
+                let hex = std::str::from_utf8(&bytes[i + 2..i + 4]).unwrap_or("");
+                if let Ok(b) = u8::from_str_radix(hex, 16) {
+                    out.push(b);
+                    i += 4;
+                    continue;
+                }
+            }
+            if bytes[i + 1] == b'u' && bytes.get(i + 2) == Some(&b'{') {
+                if let Some(len) = bytes[i + 3..].iter().position(|b| *b == b'}') {
+                    let hex = std::str::from_utf8(&bytes[i + 3..i + 3 + len]).unwrap_or("");
+                    if let Some(c) = u32::from_str_radix(hex, 16).ok().and_then(char::from_u32) {
+                        out.extend(c.to_string().as_bytes());
+                        i += 4 + len;
+                        continue;
+                    }
+                }
+            }
+        }
+        out.push(bytes[i]);
+        i += 1;
+    }
+    OsString::from_vec(out)
+}
+
+/// How names are quoted in output meant for humans, `quoting_style` in the config
+#[derive(Debug, PartialEq)]
+pub enum QuotingStyle {
+    /// always in double quotes, special characters escaped like in C
+    C,
+    /// bare when safe, otherwise quoted so it can be pasted into a shell
+    Shell,
+}
+
+impl QuotingStyle {
+    pub fn current() -> QuotingStyle {
+        match env::var("qs").as_deref() {
+            Ok("c") => QuotingStyle::C,
+            _ => QuotingStyle::Shell,
+        }
+    }
+}
+
+/// A file name quoted for the terminal in the configured style. Nothing in
+/// the result can move the cursor, change colours or start a new line.
+pub fn quote(s: &OsStr) -> String {
+    match QuotingStyle::current() {
+        QuotingStyle::C => format!("\"{}\"", escape(s)),
+        QuotingStyle::Shell => shell_quote(s),
+    }
+}
+
+pub fn quote_path(p: &Path) -> String {
+    quote(p.as_os_str())
+}
+
+fn shell_quote(s: &OsStr) -> String {
+    let bytes = s.as_bytes();
+    let is_safe = |b: &u8| b.is_ascii_alphanumeric() || b"/._-+,:@%=".contains(b);
+    if !bytes.is_empty() && bytes.iter().all(is_safe) {
+        return s.to_string_lossy().into_owned();
+    }
+    let needs_ansi_c = match s.to_str() {
+        Some(text) => text.chars().any(|c| c.is_control() || is_bidi_control(c)),
+        None => true,
+    };
+    if !needs_ansi_c {
+        return format!("'{}'", s.to_string_lossy().replace('\'', "'\\''"));
+    }
+    // bash's $'...' understands the same escapes as C, except \u{...}
+    let mut out = String::from("$'");
+    for chunk in bytes.utf8_chunks() {
+        for c in chunk.valid().chars() {
+            match c {
+                '\'' => out.push_str("\\'"),
+                '\\' => out.push_str("\\\\"),
+                '\n' => out.push_str("\\n"),
+                '\r' => out.push_str("\\r"),
+                '\t' => out.push_str("\\t"),
+                c if c.is_control() || is_bidi_control(c) => {
+                    let mut buf = [0_u8; 4];
+                    for b in c.encode_utf8(&mut buf).as_bytes() {
+                        out.push_str(&format!("\\x{:02x}", b));
+                    }
+                }
+                c => out.push(c),
+            }
+        }
+        for b in chunk.invalid() {
+            out.push_str(&format!("\\x{:02x}", b));
+        }
+    }
+    out.push('\'');
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn os(bytes: &[u8]) -> &OsStr {
+        OsStr::from_bytes(bytes)
+    }
+
+    #[test]
+    fn escape_round_trips() {
+        let names: [&[u8]; 8] = [
+            b"plain.txt",
+            b"a\"b\\c",
+            b"new\nline\ttab\rreturn",
+            b"\x1b[31mred",
+            "\u{202e}gnp.exe".as_bytes(),
+            "c1\u{85}control".as_bytes(),
+            b"latin1 \xe9t\xe9",
+            b"\\x41 \\n \\u{41}",
+        ];
+        for name in names {
+            assert_eq!(unescape(&escape(os(name))), os(name), "{:?}", name);
+        }
+    }
+
+    #[test]
+    fn escape_forms() {
+        assert_eq!(escape(os(b"a\nb\tc")), "a\\nb\\tc");
+        assert_eq!(escape(os(b"say \"hi\"")), "say \\\"hi\\\"");
+        assert_eq!(escape(os(b"\x1b\x7f")), "\\x1b\\x7f");
+        assert_eq!(escape(os(b"\xff")), "\\xff");
+        assert_eq!(escape(OsStr::new("\u{202e}")), "\\u{202e}");
+        assert_eq!(escape(OsStr::new("日本")), "日本");
+    }
+
+    #[test]
+    fn shell_quoting() {
+        assert_eq!(shell_quote(OsStr::new("dir/file-1.txt")), "dir/file-1.txt");
+        assert_eq!(shell_quote(OsStr::new("a b")), "'a b'");
+        assert_eq!(shell_quote(OsStr::new("it's")), "'it'\\''s'");
+        assert_eq!(shell_quote(OsStr::new("")), "''");
+        assert_eq!(shell_quote(os(b"a\nb")), "$'a\\nb'");
+        assert_eq!(shell_quote(os(b"it's\x1b")), "$'it\\'s\\x1b'");
+        assert_eq!(shell_quote(os(b"\xff")), "$'\\xff'");
+    }
+
+    #[test]
+    fn printable_keeps_text() {
+        assert_eq!(printable("a\t\"b\"\\c"), "a\t\"b\"\\c");
+        assert_eq!(printable("\x1b[2Jx\r"), "\\x1b[2Jx\\r");
+    }
+}
@@ -0,0 +1,328 @@
+use crate::display::{escape_path, quote, quote_path};
+use crate::error::AppError;
+use crate::history::{self, parse_last_line, Deletion};
+use crate::info::{self, TrashInfo};
+use crate::{change_file_permissions, get_type};
+use std::{
+    env,
+    ffi::{OsStr, OsString},
+    fs::{self, File},
+    io::Write,
+    os::unix::{
+        ffi::OsStrExt,
+        fs::{MetadataExt, PermissionsExt},
+    },
+    path::{Path, PathBuf},
+};
+
+/// Cross-check `<trash home>/files` against the records in `<trash home>/info`,
+/// `.last` and the log, print every discrepancy and, with `fix`, repair what
+/// can be repaired.
+pub fn doctor(mut log: &File, now: &str, fix: bool) -> Result<(), AppError> {
+    let th = PathBuf::from(env::var("th").unwrap());
+    let tc = PathBuf::from(env::var("tc").unwrap());
+    let user: String = env::var("USER").unwrap_or("default".to_string());
+    let deletions = history::deletions(&th)?;
+    let path_last = th.join(".last");
+    let last_lines = last_lines(&th)?;
+    let mut problems: usize = 0;
+    let mut fixed: usize = 0;
+
+    for name in trash_names(&tc)? {
+        let stored = tc.join(&name);
+        // records are named after ids, which are always UTF-8
+        if let Some(record) = name.to_str().and_then(|id| info::load(&th, id).ok()) {
+            unlock(&name, &stored, record.mode, fix, &mut problems, &mut fixed)?;
+            if history::latest_for(&deletions, &record.id).is_none() {
+                problems += 1;
+                println!("unlogged: the deletion of {} is missing from the log", quote(&name));
+                if fix {
+                    let info_log = format!(
+                        "{} {} rebuilt record of {} \"{}\" ${:o}$ => {}\n",
+                        now,
+                        &user,
+                        record.kind,
+                        escape_path(&record.path),
+                        record.mode % 512,
+                        &record.id
+                    );
+                    log.write_all(info_log.as_bytes())?;
+                    fixed += 1;
+                }
+            }
+            continue;
+        }
+        problems += 1;
+        let md = stored.symlink_metadata()?;
+        match rebuild(&name, &deletions, &last_lines)? {
+            Some(record) => {
+                println!(
+                    "orphan: {} has no record, it came from {}",
+                    quote(&name),
+                    quote_path(&record.path)
+                );
+                if fix {
+                    info::save(&th, &record)?;
+                    println!("\trebuilt the record of {}", quote(&name));
+                    fixed += 1;
+                }
+                // the log knows the mode an older version took away
+                unlock(&name, &stored, record.mode, fix, &mut problems, &mut fixed)?;
+            }
+            None => {
+                let mode = md.permissions().mode() % 512;
+                println!(
+                    "orphan: {} has no record, {} (mode {:03o})",
+                    quote(&name),
+                    untraced(&name),
+                    mode
+                );
+                // without a record nothing will ever restore the mode, so an
+                // item locked down by an older version stays unusable. Other
+                // modes are the item's own and are left alone.
+                let locked = mode == 0 || (md.is_dir() && mode == 0o600);
+                if locked && !md.file_type().is_symlink() {
+                    problems += 1;
+                    println!("broken mode: {} is not accessible (mode {:03o})", quote(&name), mode);
+                    if fix {
+                        let readable = if md.is_dir() { 0o755 } else { 0o644 };
+                        match change_file_permissions(&stored, readable) {
+                            Ok(_) => {
+                                println!("\treset {} to mode {:03o}", quote(&name), readable);
+                                fixed += 1;
+                            }
+                            Err(e) => println!("\tcould not reset {} to mode {:03o}: {}", quote(&name), readable, e),
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    for id in info::ids(&th)? {
+        if tc.join(&id).symlink_metadata().is_ok() {
+            continue;
+        }
+        problems += 1;
+        println!("dangling: the record {} has no item in the trash", quote(OsStr::new(&id)));
+        if fix {
+            info::remove(&th, &id)?;
+            fixed += 1;
+        }
+    }
+
+    let mut kept: Vec<&OsString> = Vec::new();
+    for line in &last_lines {
+        let stored = match parse_last_line(line) {
+            Some(record) => record.stored,
+            None => tc.join(line),
+        };
+        if line.is_empty() || stored.symlink_metadata().is_err() || !is_in(&stored, &tc) {
+            problems += 1;
+            println!("dangling: .last points at {} which is not in the trash", quote(line));
+            if fix {
+                fixed += 1;
+                continue;
+            }
+        }
+        kept.push(line);
+    }
+    if fix && kept.len() != last_lines.len() {
+        if kept.is_empty() {
+            fs::remove_file(&path_last)?;
+        } else {
+            let mut content: Vec<u8> = Vec::new();
+            for line in kept {
+                content.extend(line.as_bytes());
+                content.push(b'\n');
+            }
+            fs::write(&path_last, content)?;
+        }
+    }
+
+    if problems == 0 {
+        println!("no problems found");
+        return Ok(());
+    }
+    if fix {
+        let info_log = format!(
+            "{} {} ran doctor: {} problems found, {} fixed\n",
+            now, &user, problems, fixed
+        );
+        log.write_all(info_log.as_bytes())?;
+        println!("{} problems found, {} fixed", problems, fixed);
+    } else {
+        println!("{} problems found, run with --fix to repair", problems);
+    }
+    if problems > fixed {
+        return Err(AppError {
+            code: -14,
+            message: format!("{} problems left in the trash", problems - fixed),
+        });
+    }
+    Ok(())
+}
+
+/// `rmrs recover`: rebuild the record of every item in the trash can that
+/// has none from the log, or an old `.last`, and log each rebuilt record
+pub fn recover(mut log: &File, now: &str, dry_run: bool) -> Result<(), AppError> {
+    let th = PathBuf::from(env::var("th").unwrap());
+    let tc = PathBuf::from(env::var("tc").unwrap());
+    let user: String = env::var("USER").unwrap_or("default".to_string());
+    let deletions = history::deletions(&th)?;
+    let last_lines = last_lines(&th)?;
+    let mut rebuilt: usize = 0;
+    let mut lost: usize = 0;
+    for name in trash_names(&tc)? {
+        if name.to_str().is_some_and(|id| info::load(&th, id).is_ok()) {
+            continue;
+        }
+        let Some(record) = rebuild(&name, &deletions, &last_lines)? else {
+            lost += 1;
+            println!("{} has no record, {}", quote(&name), untraced(&name));
+            continue;
+        };
+        if dry_run {
+            println!(
+                "would rebuild the record of {}, deleted from {} by {} at {}",
+                quote(&name),
+                quote_path(&record.path),
+                quote(OsStr::new(&record.user)),
+                record.deleted_time()
+            );
+            rebuilt += 1;
+            continue;
+        }
+        info::save(&th, &record)?;
+        let info_log = format!(
+            "{} {} rebuilt record of {} \"{}\" ${:o}$ => {}\n",
+            now,
+            &user,
+            record.kind,
+            escape_path(&record.path),
+            record.mode % 512,
+            &record.id
+        );
+        log.write_all(info_log.as_bytes())?;
+        println!(
+            "rebuilt the record of {}, deleted from {} by {} at {}",
+            quote(&name),
+            quote_path(&record.path),
+            quote(OsStr::new(&record.user)),
+            record.deleted_time()
+        );
+        rebuilt += 1;
+    }
+    if rebuilt == 0 && lost == 0 {
+        println!("every item in the trash has a record");
+    } else if dry_run {
+        println!("{} records can be rebuilt, run without --dry-run to do so", rebuilt);
+    } else {
+        println!("{} records rebuilt", rebuilt);
+    }
+    if lost > 0 {
+        return Err(AppError {
+            code: -14,
+            message: format!("{} items in the trash can't be traced back", lost),
+        });
+    }
+    Ok(())
+}
+
+/// Names of everything in the trash can, sorted. Items of older versions
+/// kept their own names, which needn't be UTF-8.
+fn trash_names(tc: &Path) -> Result<Vec<OsString>, AppError> {
+    let mut names: Vec<OsString> = Vec::new();
+    for entry in fs::read_dir(tc)? {
+        names.push(entry?.file_name());
+    }
+    names.sort();
+    Ok(names)
+}
+
+/// Give `<trash can>/<name>` back the mode of its record, which older
+/// versions replaced with 000 (600 for directories) while it was in the trash
+fn unlock(
+    name: &OsStr,
+    stored: &Path,
+    mode: u32,
+    fix: bool,
+    problems: &mut usize,
+    fixed: &mut usize,
+) -> Result<(), AppError> {
+    let md = stored.symlink_metadata()?;
+    // modes rebuilt from the log lack the set-id bits
+    if md.file_type().is_symlink() || md.permissions().mode() & 0o777 == mode & 0o777 {
+        return Ok(());
+    }
+    *problems += 1;
+    println!(
+        "locked: {} has mode {:03o} instead of its own {:03o}",
+        quote(name),
+        md.permissions().mode() & 0o7777,
+        mode & 0o7777
+    );
+    if fix {
+        match change_file_permissions(stored, (mode & 0o7777) as _) {
+            Ok(_) => *fixed += 1,
+            Err(e) => println!("\tcould not restore mode {:03o} of {}: {}", mode & 0o7777, quote(name), e),
+        }
+    }
+    Ok(())
+}
+
+/// The lines of `.last`, which older versions filled with `<stored> >> <original> $<mode>$`
+fn last_lines(th: &Path) -> Result<Vec<OsString>, AppError> {
+    let path_last = th.join(".last");
+    if !path_last.exists() {
+        return Ok(Vec::new());
+    }
+    let content = fs::read(&path_last)?;
+    let content = content.strip_suffix(b"\n").unwrap_or(&content);
+    if content.is_empty() {
+        return Ok(Vec::new());
+    }
+    Ok(content.split(|b| *b == b'\n').map(|l| OsStr::from_bytes(l).to_os_string()).collect())
+}
+
+/// A record for the item at `<trash can>/<name>` from what the latest
+/// deletion into that name logged, or failing that from an old `.last`.
+/// A name that isn't UTF-8 can't have a record.
+fn rebuild(name: &OsStr, deletions: &[Deletion], last_lines: &[OsString]) -> Result<Option<TrashInfo>, AppError> {
+    let Some(name) = name.to_str() else {
+        return Ok(None);
+    };
+    let stored = PathBuf::from(env::var("tc").unwrap()).join(name);
+    let md = stored.symlink_metadata()?;
+    // moving the item into the trash can changed its ctime
+    let (path, mode, kind, user, deleted_at) = match history::latest_for(deletions, name) {
+        Some(d) => (d.path.clone(), d.mode, d.kind.clone(), d.user.clone(), d.timestamp().unwrap_or(md.ctime())),
+        None => match last_lines.iter().filter_map(|l| parse_last_line(l)).find(|r| r.stored == stored) {
+            Some(r) => (r.original, r.mode, get_type(&stored), "unknown".to_string(), md.ctime()),
+            None => return Ok(None),
+        },
+    };
+    Ok(Some(TrashInfo {
+        id: name.to_string(),
+        name: path.file_name().unwrap_or(path.as_os_str()).to_os_string(),
+        path,
+        kind,
+        mode,
+        deleted_at,
+        user,
+        attrs: None,
+        parent_mode: None,
+    }))
+}
+
+/// Why [`rebuild`] found nothing for `name`
+fn untraced(name: &OsStr) -> &'static str {
+    match name.to_str() {
+        Some(_) => "its original location is unknown",
+        None => "its name is not UTF-8 so it can't get one",
+    }
+}
+
+fn is_in(stored: &Path, trash_can: &Path) -> bool {
+    stored.parent() == Some(trash_can)
+}
@@ -0,0 +1,211 @@
+use crate::display::{escape, unescape};
+use crate::error::AppError;
+use crate::info;
+use regex::Regex;
+use std::{
+    ffi::OsStr,
+    fs::File,
+    io::{BufRead, BufReader},
+    os::unix::ffi::OsStrExt,
+    path::{Path, PathBuf},
+    sync::OnceLock,
+};
+
+/// A deletion into the trash can as recorded by a line of the log
+#[derive(Debug, Clone)]
+pub struct Deletion {
+    pub time: String,
+    pub user: String,
+    pub kind: String,
+    pub path: PathBuf,
+    pub mode: u32,
+    /// name inside the trash can
+    pub name: String,
+}
+
+impl Deletion {
+    /// When the deletion was logged, seconds since the unix epoch
+    pub fn timestamp(&self) -> Option<i64> {
+        info::parse_time(self.time.get(..19)?).ok()
+    }
+}
+
+/// Every deletion recorded in `<trash home>/log`, oldest first
+pub fn deletions(trash_home: &Path) -> Result<Vec<Deletion>, AppError> {
+    let path_log = trash_home.join("log");
+    if !path_log.exists() {
+        return Ok(Vec::new());
+    }
+    Ok(entries(BufReader::new(File::open(path_log)?))?
+        .iter()
+        .filter_map(|entry| parse_deletion(entry))
+        .collect())
+}
+
+/// A log entry that put something into the trash can, in any format rmrs
+/// has written
+fn parse_deletion(entry: &str) -> Option<Deletion> {
+    static RE: OnceLock<Regex> = OnceLock::new();
+    let re = RE.get_or_init(|| {
+        Regex::new(
+            r#"(?s)^(\S+ \S+ \S+) (\S+) (deleted|recovered interrupted deletion of|rebuilt record of) (.+?) "(.*)" \$([0-7]+)\$ => (.+)$"#,
+        )
+        .unwrap()
+    });
+    let cap = re.captures(entry)?;
+    Some(Deletion {
+        time: cap[1].to_string(),
+        user: cap[2].to_string(),
+        kind: cap[4].to_string(),
+        path: logged_path(&cap[5], &cap[3] == "deleted" && !info::is_id(&cap[7])),
+        mode: u32::from_str_radix(&cap[6], 8).unwrap_or(0o644),
+        name: cap[7].to_string(),
+    })
+}
+
+/// The entries of the log. Older versions wrote paths as they were, so a
+/// name with a newline in it went on over several lines: every line that
+/// doesn't start with a time belongs to the entry before it.
+fn entries(log: impl BufRead) -> Result<Vec<String>, AppError> {
+    let starts_entry = Regex::new(r"^\d{4}-\d{2}-\d{2} \d{2}:\d{2}:\d{2} ")?;
+    let mut entries: Vec<String> = Vec::new();
+    for line in log.split(b'\n') {
+        let line = String::from_utf8_lossy(&line?).into_owned();
+        match entries.last_mut() {
+            Some(entry) if !starts_entry.is_match(&line) => {
+                entry.push('\n');
+                entry.push_str(&line);
+            }
+            _ => entries.push(line),
+        }
+    }
+    Ok(entries)
+}
+
+/// A path as written between the quotes of a log line: escaped, or by older
+/// versions as it was. Deletions into a trash name made from the file name
+/// are always that old; otherwise only an escaped path reads back to the
+/// same text.
+fn logged_path(text: &str, raw: bool) -> PathBuf {
+    if raw {
+        return PathBuf::from(text);
+    }
+    let unescaped = unescape(text);
+    if escape(&unescaped) == text {
+        PathBuf::from(unescaped)
+    } else {
+        PathBuf::from(text)
+    }
+}
+
+/// The latest deletion that put something at `<trash can>/<name>`
+pub fn latest_for<'a>(deletions: &'a [Deletion], name: &str) -> Option<&'a Deletion> {
+    deletions.iter().rev().find(|d| d.name == name)
+}
+
+/// One line of `.last`: `<stored> >> <original> $<mode>$`
+#[derive(Debug, Clone)]
+pub struct LastRecord {
+    pub stored: PathBuf,
+    pub original: PathBuf,
+    pub mode: u32,
+}
+
+/// Parse a line of `.last` as older versions wrote it; the paths in it are raw bytes
+pub fn parse_last_line(line: &OsStr) -> Option<LastRecord> {
+    let line = line.as_bytes();
+    let arrow = line.windows(4).position(|w| w == b" >> ")?;
+    let (stored, rest) = (&line[..arrow], &line[arrow + 4..]);
+    let dollar = rest.windows(2).rposition(|w| w == b" $")?;
+    let (original, mode) = (&rest[..dollar], &rest[dollar + 2..]);
+    let mode = u32::from_str_radix(std::str::from_utf8(mode.strip_suffix(b"$")?).ok()?, 8).ok()?;
+    Some(LastRecord {
+        stored: PathBuf::from(OsStr::from_bytes(stored)),
+        original: PathBuf::from(OsStr::from_bytes(original)),
+        mode,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn parses_current_deletions() {
+        let d = parse_deletion(
+            "2026-10-19 03:44:30 +08:00:00 tester deleted file \"/w/a\\\"b\\nc\" $644$ => 20261019T034430-d282b991",
+        )
+        .unwrap();
+        assert_eq!(d.time, "2026-10-19 03:44:30 +08:00:00");
+        assert_eq!(d.user, "tester");
+        assert_eq!(d.kind, "file");
+        assert_eq!(d.path, PathBuf::from("/w/a\"b\nc"));
+        assert_eq!(d.mode, 0o644);
+        assert_eq!(d.name, "20261019T034430-d282b991");
+        assert_eq!(
+            d.timestamp(),
+            Some(info::parse_time("2026-10-19 03:44:30").unwrap())
+        );
+    }
+
+    #[test]
+    fn reads_old_paths_as_they_were() {
+        let d = parse_deletion(
+            "2023-01-02 03:04:06 +08:00:00 bob deleted directory \"/x/a\\nb\\x41\" $755$ => a2",
+        )
+        .unwrap();
+        assert_eq!(d.path, PathBuf::from("/x/a\\nb\\x41"));
+        assert_eq!(d.kind, "directory");
+        assert_eq!(d.name, "a2");
+        // ids without escaping, from the versions in between
+        let d = parse_deletion("2023-01-02 03:04:06 +08:00:00 bob deleted file \"/x/a\\b\" $644$ => 20230102T030406-00c0ffee")
+            .unwrap();
+        assert_eq!(d.path, PathBuf::from("/x/a\\b"));
+    }
+
+    #[test]
+    fn parses_recovered_and_rebuilt_entries() {
+        let d = parse_deletion(
+            "2026-10-19 03:44:30 +08:00:00 root recovered interrupted deletion of symlink \"/l\" $777$ => 20261019T034430-00000001",
+        )
+        .unwrap();
+        assert_eq!((d.kind.as_str(), d.mode), ("symlink", 0o777));
+        let d = parse_deletion(
+            "2026-10-19 03:44:30 +08:00:00 root rebuilt record of file \"/x/a\\\\nb\" $600$ => a2",
+        )
+        .unwrap();
+        assert_eq!(d.path, PathBuf::from("/x/a\\nb"));
+        assert!(parse_deletion(
+            "2026-10-19 03:44:30 +08:00:00 root tried to delete file \"/x\" while I refused: busy"
+        )
+        .is_none());
+        assert!(parse_deletion(
+            "2026-10-19 03:44:30 +08:00:00 root restored file \"/x\" $644$ <= a2"
+        )
+        .is_none());
+    }
+
+    #[test]
+    fn joins_old_entries_spread_over_lines() {
+        let log = "2023-01-02 03:04:05 +08:00:00 bob deleted file \"/x/one\ntwo\" $644$ => one2\n\
+                   2023-01-02 03:04:06 +08:00:00 bob cleaned trash can\n";
+        let entries = entries(Cursor::new(log)).unwrap();
+        assert_eq!(entries.len(), 2);
+        assert!(entries[1].ends_with("cleaned trash can"));
+        let d = parse_deletion(&entries[0]).unwrap();
+        assert_eq!(d.path, PathBuf::from("/x/one\ntwo"));
+        assert_eq!(d.name, "one2");
+    }
+
+    #[test]
+    fn parses_old_last_lines() {
+        let r = parse_last_line(OsStr::new("/t/files/a b2 >> /w/a b $644$")).unwrap();
+        assert_eq!(r.stored, PathBuf::from("/t/files/a b2"));
+        assert_eq!(r.original, PathBuf::from("/w/a b"));
+        assert_eq!(r.mode, 0o644);
+        assert!(parse_last_line(OsStr::new("20261019T034430-d282b991")).is_none());
+        let r = parse_last_line(OsStr::from_bytes(b"/t/files/caf\xe9 >> /w/caf\xe9 $600$")).unwrap();
+        assert_eq!(r.stored, Path::new(OsStr::from_bytes(b"/t/files/caf\xe9")));
+    }
+}
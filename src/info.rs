@@ -0,0 +1,359 @@
+use crate::attrs::Attrs;
+use crate::display::quote_path;
+use crate::error::AppError;
+use serde::{Deserialize, Serialize};
+use std::{
+    ffi::OsString,
+    fs::{self, File},
+    io::Read,
+    path::{Component, Path, PathBuf},
+};
+use time as Dime;
+use Dime::{format_description, macros::offset};
+
+const INFO_DIR: &str = "info";
+
+/// What rmrs remembers about an item in the trash can, stored as
+/// `<trash home>/info/<id>.toml` next to the item at `<trash home>/files/<id>`
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct TrashInfo {
+    /// name of the item inside the trash can
+    pub id: String,
+    /// file name the item had before it was deleted
+    #[serde(with = "raw")]
+    pub name: OsString,
+    /// absolute path the item had before it was deleted
+    #[serde(with = "raw")]
+    pub path: PathBuf,
+    pub kind: String,
+    pub mode: u32,
+    /// seconds since the unix epoch
+    pub deleted_at: i64,
+    pub user: String,
+    /// owner, timestamps and extended attributes before deletion,
+    /// missing in records of older versions
+    #[serde(default)]
+    pub attrs: Option<Attrs>,
+    /// mode of the directory the item was deleted from, so that a restore
+    /// can recreate it as it was
+    #[serde(default)]
+    pub parent_mode: Option<u32>,
+}
+
+/// File names are bytes, not text. Names that are valid UTF-8 are stored as
+/// strings so records stay readable, any other name as an array of its bytes.
+mod raw {
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+    use std::ffi::{OsStr, OsString};
+    use std::os::unix::ffi::{OsStrExt, OsStringExt};
+
+    #[derive(Deserialize, Serialize)]
+    #[serde(untagged)]
+    enum Raw {
+        Text(String),
+        Bytes(Vec<u8>),
+    }
+
+    pub fn serialize<T: AsRef<OsStr>, S: Serializer>(value: &T, serializer: S) -> Result<S::Ok, S::Error> {
+        let value = value.as_ref();
+        match value.to_str() {
+            Some(text) => Raw::Text(text.to_string()),
+            None => Raw::Bytes(value.as_bytes().to_vec()),
+        }
+        .serialize(serializer)
+    }
+
+    pub fn deserialize<'de, T: From<OsString>, D: Deserializer<'de>>(deserializer: D) -> Result<T, D::Error> {
+        Ok(T::from(match Raw::deserialize(deserializer)? {
+            Raw::Text(text) => OsString::from(text),
+            Raw::Bytes(bytes) => OsString::from_vec(bytes),
+        }))
+    }
+}
+
+impl TrashInfo {
+    pub fn stored(&self) -> PathBuf {
+        PathBuf::from(std::env::var("tc").unwrap()).join(&self.id)
+    }
+
+    /// When the item was deleted, in the time zone of the log
+    pub fn deleted_time(&self) -> String {
+        local_time(self.deleted_at)
+    }
+
+    /// Where `sub`, a path relative to the item, is in the trash can. It may
+    /// neither climb out of the item nor go through a symlink.
+    pub fn locate(&self, sub: &Path) -> Result<PathBuf, String> {
+        if !sub.components().all(|c| matches!(c, Component::Normal(_))) {
+            return Err(format!("{} is not a plain path inside the item", quote_path(sub)));
+        }
+        let mut found = self.stored();
+        for component in sub.components() {
+            if !found.symlink_metadata().map(|md| md.is_dir()).unwrap_or(false) {
+                return Err(format!("there is no {} in {}", quote_path(sub), self.id));
+            }
+            found.push(component);
+        }
+        if found.symlink_metadata().is_err() {
+            return Err(format!("there is no {} in {}", quote_path(sub), self.id));
+        }
+        Ok(found)
+    }
+}
+
+/// Seconds since the epoch for a time given as `YYYY-MM-DD`, `YYYY-MM-DD HH:MM`,
+/// `YYYY-MM-DD HH:MM:SS` or, for today, `HH:MM`, in the time zone of the log
+pub fn parse_time(text: &str) -> Result<i64, AppError> {
+    parse_period(text).map(|(start, _)| start)
+}
+
+/// The last second of the day, minute or second `text` names, as an
+/// inclusive upper bound: `--until 14:30` covers 14:30:59 too
+pub fn parse_time_end(text: &str) -> Result<i64, AppError> {
+    parse_period(text).map(|(start, len)| start + len - 1)
+}
+
+/// The start of the time `text` names and how many seconds it spans
+fn parse_period(text: &str) -> Result<(i64, i64), AppError> {
+    let invalid = || AppError {
+        code: -21,
+        message: format!("can't read \"{}\" as a time, use YYYY-MM-DD [HH:MM[:SS]] or HH:MM", text),
+    };
+    let byte = |n: u32| u8::try_from(n).map_err(|_| invalid());
+    let numbers = |part: &str, sep: char| -> Option<Vec<u32>> { part.split(sep).map(|n| n.parse().ok()).collect() };
+    let (date, time) = match text.trim().split_once(' ') {
+        Some((date, time)) => (Some(date), Some(time)),
+        None if text.contains(':') => (None, Some(text.trim())),
+        None => (Some(text.trim()), None),
+    };
+    let date = match date {
+        Some(date) => match numbers(date, '-').as_deref() {
+            Some([y, m, d]) => {
+                let month = Dime::Month::try_from(byte(*m)?).map_err(|_| invalid())?;
+                Dime::Date::from_calendar_date(*y as i32, month, byte(*d)?).map_err(|_| invalid())?
+            }
+            _ => return Err(invalid()),
+        },
+        None => Dime::OffsetDateTime::now_utc().to_offset(offset!(+8)).date(),
+    };
+    let (time, len) = match time.map(|t| numbers(t, ':')) {
+        None => (Dime::Time::MIDNIGHT, 86400),
+        Some(Some(hms)) if hms.len() == 2 || hms.len() == 3 => (
+            Dime::Time::from_hms(byte(hms[0])?, byte(hms[1])?, byte(*hms.get(2).unwrap_or(&0))?).map_err(|_| invalid())?,
+            if hms.len() == 2 { 60 } else { 1 },
+        ),
+        _ => return Err(invalid()),
+    };
+    Ok((Dime::PrimitiveDateTime::new(date, time).assume_offset(offset!(+8)).unix_timestamp(), len))
+}
+
+/// Seconds since the unix epoch as a time in the time zone of the log
+pub fn local_time(timestamp: i64) -> String {
+    Dime::OffsetDateTime::from_unix_timestamp(timestamp)
+        .unwrap_or(Dime::OffsetDateTime::UNIX_EPOCH)
+        .to_offset(offset!(+8))
+        .format(&format_description::parse("[year]-[month]-[day] [hour]:[minute]:[second]").unwrap())
+        .unwrap_or_default()
+}
+
+/// A fresh id for an item deleted at `deleted_at`: the deletion time followed
+/// by a random suffix, e.g. `20231024T153000-9f86d081`
+pub fn new_id(deleted_at: i64) -> Result<String, AppError> {
+    let stamp = Dime::OffsetDateTime::from_unix_timestamp(deleted_at)
+        .unwrap_or(Dime::OffsetDateTime::UNIX_EPOCH)
+        .to_offset(offset!(+8))
+        .format(&format_description::parse(
+            "[year][month][day]T[hour][minute][second]",
+        )?)?;
+    let mut random = [0_u8; 4];
+    File::open("/dev/urandom")?.read_exact(&mut random)?;
+    Ok(format!("{}-{:08x}", stamp, u32::from_be_bytes(random)))
+}
+
+/// Whether `name` has the form of an id made by [`new_id`]; older versions
+/// named items in the trash can after the file itself
+pub fn is_id(name: &str) -> bool {
+    let b = name.as_bytes();
+    b.len() == 24
+        && b[8] == b'T'
+        && b[15] == b'-'
+        && b[..8].iter().chain(&b[9..15]).all(u8::is_ascii_digit)
+        && b[16..].iter().all(|c| c.is_ascii_digit() || (b'a'..=b'f').contains(c))
+}
+
+fn info_dir(trash_home: &Path) -> PathBuf {
+    trash_home.join(INFO_DIR)
+}
+
+fn info_path(trash_home: &Path, id: &str) -> PathBuf {
+    info_dir(trash_home).join(format!("{}.toml", id))
+}
+
+pub fn save(trash_home: &Path, info: &TrashInfo) -> Result<(), AppError> {
+    fs::create_dir_all(info_dir(trash_home))?;
+    let path = info_path(trash_home, &info.id);
+    let path_tmp = path.with_extension("toml.tmp");
+    fs::write(&path_tmp, toml::to_string(info)?)?;
+    fs::rename(path_tmp, path)?;
+    Ok(())
+}
+
+pub fn load(trash_home: &Path, id: &str) -> Result<TrashInfo, AppError> {
+    let content = fs::read_to_string(info_path(trash_home, id))?;
+    toml::from_str::<TrashInfo>(&content).map_err(|e| AppError {
+        code: -15,
+        message: format!("broken record of \"{}\": {}", id, e.message()),
+    })
+}
+
+pub fn remove(trash_home: &Path, id: &str) -> Result<(), AppError> {
+    match fs::remove_file(info_path(trash_home, id)) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+/// Move the record `id` from one trash home to another, if there is one
+pub fn transfer(from: &Path, to: &Path, id: &str) -> Result<(), AppError> {
+    fs::create_dir_all(info_dir(to))?;
+    match fs::rename(info_path(from, id), info_path(to, id)) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+/// Ids of every record, whether or not its item is still in the trash can
+pub fn ids(trash_home: &Path) -> Result<Vec<String>, AppError> {
+    let mut ids = Vec::new();
+    if !info_dir(trash_home).exists() {
+        return Ok(ids);
+    }
+    for entry in fs::read_dir(info_dir(trash_home))? {
+        let file_name = entry?.file_name();
+        if let Some(id) = file_name.to_string_lossy().strip_suffix(".toml") {
+            ids.push(id.to_string());
+        }
+    }
+    ids.sort();
+    Ok(ids)
+}
+
+/// Every readable record, oldest deletion first
+pub fn all(trash_home: &Path) -> Result<Vec<TrashInfo>, AppError> {
+    let mut infos: Vec<TrashInfo> = ids(trash_home)?
+        .iter()
+        .filter_map(|id| load(trash_home, id).ok())
+        .collect();
+    infos.sort_by(|a, b| a.deleted_at.cmp(&b.deleted_at).then(a.id.cmp(&b.id)));
+    Ok(infos)
+}
+
+/// Find the record an id, or an unambiguous prefix of one, refers to
+pub fn resolve(trash_home: &Path, query: &str) -> Result<TrashInfo, AppError> {
+    // every id starts with the empty prefix
+    if query.is_empty() {
+        return Err(AppError {
+            code: -16,
+            message: "no item given".to_string(),
+        });
+    }
+    if info_path(trash_home, query).exists() {
+        return load(trash_home, query);
+    }
+    let matched: Vec<String> = ids(trash_home)?
+        .into_iter()
+        .filter(|id| id.starts_with(query))
+        .collect();
+    match matched.len() {
+        1 => load(trash_home, &matched[0]),
+        0 => Err(AppError {
+            code: -16,
+            message: format!("no item \"{}\" in the trash", query),
+        }),
+        _ => Err(AppError {
+            code: -16,
+            message: format!("\"{}\" matches {} items, be more specific", query, matched.len()),
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_times_in_the_log_time_zone() {
+        assert_eq!(parse_time("2024-02-29 14:30").unwrap(), 1709188200);
+        assert_eq!(parse_time("2024-02-29 14:30:00").unwrap(), 1709188200);
+        assert_eq!(
+            parse_time("2024-02-29").unwrap(),
+            1709188200 - (14 * 60 + 30) * 60
+        );
+        assert_eq!(parse_time(" 2023-01-02 03:04:06 ").unwrap(), 1672599846);
+        let today = parse_time("00:00").unwrap();
+        assert_eq!(parse_time("14:30").unwrap(), today + (14 * 60 + 30) * 60);
+    }
+
+    #[test]
+    fn time_ends_cover_the_precision_given() {
+        let start = parse_time("2024-02-29 14:30").unwrap();
+        assert_eq!(parse_time_end("2024-02-29 14:30").unwrap(), start + 59);
+        assert_eq!(parse_time_end("2024-02-29 14:30:00").unwrap(), start);
+        assert_eq!(
+            parse_time_end("2024-02-29").unwrap(),
+            parse_time("2024-03-01").unwrap() - 1
+        );
+    }
+
+    #[test]
+    fn rejects_bad_times() {
+        for text in [
+            "",
+            "tomorrow",
+            "2023-02-29",
+            "2024-13-01",
+            "2024-1-1 25:00",
+            "14",
+            "14:30:00:00",
+            "2024-01-01 14",
+        ] {
+            assert_eq!(
+                parse_time(text).map_err(|e| e.code).unwrap_err(),
+                -21,
+                "{:?}",
+                text
+            );
+        }
+    }
+
+    #[test]
+    fn recognises_ids() {
+        assert!(is_id("20231024T153000-9f86d081"));
+        assert!(!is_id("20231024T153000-9F86D081"));
+        assert!(!is_id("20231024-153000-9f86d081"));
+        assert!(!is_id("notes2.txt"));
+        assert!(!is_id(""));
+    }
+
+    #[test]
+    fn resolves_ids_and_prefixes() {
+        let home = std::env::temp_dir().join(format!("rmrs-info-test-{}", std::process::id()));
+        let record = TrashInfo {
+            id: "20240101T000000-0123abcd".to_string(),
+            name: OsString::from("a.txt"),
+            path: PathBuf::from("/w/a.txt"),
+            kind: "file".to_string(),
+            mode: 0o100644,
+            deleted_at: 0,
+            user: "tester".to_string(),
+            attrs: None,
+            parent_mode: None,
+        };
+        save(&home, &record).unwrap();
+        assert_eq!(resolve(&home, &record.id).unwrap().path, record.path);
+        assert_eq!(resolve(&home, "20240101T").unwrap().id, record.id);
+        assert_eq!(resolve(&home, "").map_err(|e| e.code).unwrap_err(), -16);
+        assert_eq!(resolve(&home, "2025").map_err(|e| e.code).unwrap_err(), -16);
+        fs::remove_dir_all(&home).unwrap();
+    }
+}
@@ -0,0 +1,35 @@
+use crate::error::AppError;
+use libc::{c_int, sigaction, sigemptyset, SA_RESETHAND, SIGINT, SIGTERM};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn on_signal(_: c_int) {
+    INTERRUPTED.store(true, Ordering::SeqCst);
+}
+
+/// Catch SIGINT and SIGTERM so that the item being worked on is finished
+/// before rmrs stops. The handler only fires once: a second signal kills
+/// the process as usual.
+pub fn install() {
+    unsafe {
+        let mut action: sigaction = std::mem::zeroed();
+        action.sa_sigaction = on_signal as extern "C" fn(c_int) as usize;
+        action.sa_flags = SA_RESETHAND;
+        sigemptyset(&mut action.sa_mask);
+        sigaction(SIGINT, &action, std::ptr::null_mut());
+        sigaction(SIGTERM, &action, std::ptr::null_mut());
+    }
+}
+
+/// Whether a signal has asked us to stop
+pub fn interrupted() -> bool {
+    INTERRUPTED.load(Ordering::SeqCst)
+}
+
+pub fn interrupted_error() -> AppError {
+    AppError {
+        code: -13,
+        message: "interrupted".to_string(),
+    }
+}
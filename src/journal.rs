@@ -0,0 +1,320 @@
+use crate::error::AppError;
+use std::{
+    ffi::{OsStr, OsString},
+    fs::{File, OpenOptions},
+    io::{BufRead, BufReader, Write},
+    os::unix::ffi::OsStrExt,
+    path::{Path, PathBuf},
+};
+
+const JOURNAL_FILE: &str = "journal";
+
+/// An operation on the trash that is written to the journal before it starts
+#[derive(Debug, Clone, PartialEq)]
+pub enum Op {
+    /// `src` is being renamed to `<trash can>/<name>`, `mode` is its mode before that
+    Move { src: PathBuf, name: String, mode: u32 },
+    /// `<trash can>/<name>` is being renamed back to `dst`
+    Restore { name: String, dst: PathBuf, mode: u32 },
+    /// `path` is being removed from disc
+    Purge { path: PathBuf },
+    /// `<trash can>/<name>` and its record are being moved into the purged batch `batch`
+    Stage { name: OsString, batch: PathBuf },
+    /// `<batch>/files/<name>` and its record are being moved back into the trash can
+    Unstage { name: OsString, batch: PathBuf },
+}
+
+/// Write-ahead journal of trash operations.
+///
+/// Every operation gets a `begin` line, synced to disc before the operation
+/// starts, and an `end` line once everything that belongs to it (the log
+/// line, the `.last` record) has been written. Whatever has a `begin` but no
+/// `end` was cut short and is settled by the next run.
+pub struct Journal {
+    file: File,
+    next: u64,
+    open: Vec<u64>,
+}
+
+impl Journal {
+    /// Open the journal for appending. Call [`Journal::pending`] and
+    /// [`Journal::clear`] first so that sequence numbers don't clash.
+    pub fn open(trash_home: &Path) -> Result<Journal, AppError> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(trash_home.join(JOURNAL_FILE))?;
+        Ok(Self {
+            file,
+            next: 0,
+            open: Vec::new(),
+        })
+    }
+
+    /// Operations that were begun but never ended, in the order they started
+    pub fn pending(trash_home: &Path) -> Result<Vec<Op>, AppError> {
+        let path = trash_home.join(JOURNAL_FILE);
+        if !path.exists() {
+            return Ok(Vec::new());
+        }
+        let mut begun: Vec<(u64, Op)> = Vec::new();
+        let reader = BufReader::new(File::open(path)?);
+        for line in reader.split(b'\n') {
+            let line = line?;
+            let fields: Vec<Vec<u8>> = line.split(|b| *b == b'\t').map(unescape).collect();
+            let seq = match fields.get(1).and_then(|s| String::from_utf8_lossy(s).parse::<u64>().ok()) {
+                Some(seq) => seq,
+                // a torn line from a crash while writing it
+                None => continue,
+            };
+            match fields[0].as_slice() {
+                b"begin" => {
+                    if let Some(op) = parse_op(&fields[2..]) {
+                        begun.push((seq, op));
+                    }
+                }
+                b"end" => begun.retain(|(s, _)| *s != seq),
+                _ => {}
+            }
+        }
+        Ok(begun.into_iter().map(|(_, op)| op).collect())
+    }
+
+    /// Throw the journal away once everything in it has been settled
+    pub fn clear(trash_home: &Path) -> Result<(), AppError> {
+        File::create(trash_home.join(JOURNAL_FILE))?;
+        Ok(())
+    }
+
+    /// Record `op` and make sure it is on disc before returning
+    pub fn begin(&mut self, op: &Op) -> Result<u64, AppError> {
+        let seq = self.next;
+        self.next += 1;
+        let mut fields: Vec<Vec<u8>> = vec![b"begin".to_vec(), seq.to_string().into_bytes()];
+        match op {
+            Op::Move { src, name, mode } => {
+                fields.push(b"move".to_vec());
+                fields.push(src.as_os_str().as_bytes().to_vec());
+                fields.push(name.as_bytes().to_vec());
+                fields.push(format!("{:o}", mode).into_bytes());
+            }
+            Op::Restore { name, dst, mode } => {
+                fields.push(b"restore".to_vec());
+                fields.push(name.as_bytes().to_vec());
+                fields.push(dst.as_os_str().as_bytes().to_vec());
+                fields.push(format!("{:o}", mode).into_bytes());
+            }
+            Op::Purge { path } => {
+                fields.push(b"purge".to_vec());
+                fields.push(path.as_os_str().as_bytes().to_vec());
+            }
+            Op::Stage { name, batch } => {
+                fields.push(b"stage".to_vec());
+                fields.push(name.as_bytes().to_vec());
+                fields.push(batch.as_os_str().as_bytes().to_vec());
+            }
+            Op::Unstage { name, batch } => {
+                fields.push(b"unstage".to_vec());
+                fields.push(name.as_bytes().to_vec());
+                fields.push(batch.as_os_str().as_bytes().to_vec());
+            }
+        }
+        self.write_line(&fields)?;
+        self.file.sync_data()?;
+        self.open.push(seq);
+        Ok(seq)
+    }
+
+    /// Mark a single operation as done
+    pub fn end(&mut self, seq: u64) -> Result<(), AppError> {
+        self.write_line(&[b"end".to_vec(), seq.to_string().into_bytes()])?;
+        self.open.retain(|s| *s != seq);
+        Ok(())
+    }
+
+    /// Mark every operation that is still open as done
+    pub fn commit(&mut self) -> Result<(), AppError> {
+        for seq in self.open.clone() {
+            self.end(seq)?;
+        }
+        self.file.sync_data()?;
+        Ok(())
+    }
+
+    fn write_line(&mut self, fields: &[Vec<u8>]) -> Result<(), AppError> {
+        let mut line: Vec<u8> = Vec::new();
+        for (i, field) in fields.iter().enumerate() {
+            if i > 0 {
+                line.push(b'\t');
+            }
+            line.extend(escape(field));
+        }
+        line.push(b'\n');
+        self.file.write_all(&line)?;
+        Ok(())
+    }
+}
+
+fn parse_op(fields: &[Vec<u8>]) -> Option<Op> {
+    let path = |b: &Vec<u8>| PathBuf::from(OsStr::from_bytes(b));
+    let text = |b: &Vec<u8>| String::from_utf8_lossy(b).into_owned();
+    let name = |b: &Vec<u8>| OsStr::from_bytes(b).to_os_string();
+    let mode = |b: &Vec<u8>| u32::from_str_radix(&String::from_utf8_lossy(b), 8).ok();
+    match (fields.first()?.as_slice(), fields.len()) {
+        (b"move", 4) => Some(Op::Move {
+            src: path(&fields[1]),
+            name: text(&fields[2]),
+            mode: mode(&fields[3])?,
+        }),
+        (b"restore", 4) => Some(Op::Restore {
+            name: text(&fields[1]),
+            dst: path(&fields[2]),
+            mode: mode(&fields[3])?,
+        }),
+        (b"purge", 2) => Some(Op::Purge {
+            path: path(&fields[1]),
+        }),
+        (b"stage", 3) => Some(Op::Stage {
+            name: name(&fields[1]),
+            batch: path(&fields[2]),
+        }),
+        (b"unstage", 3) => Some(Op::Unstage {
+            name: name(&fields[1]),
+            batch: path(&fields[2]),
+        }),
+        _ => None,
+    }
+}
+
+/// Keep a field on one line: backslash, tab and newline are escaped
+fn escape(field: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(field.len());
+    for b in field {
+        match b {
+            b'\\' => out.extend(b"\\\\"),
+            b'\t' => out.extend(b"\\t"),
+            b'\n' => out.extend(b"\\n"),
+            _ => out.push(*b),
+        }
+    }
+    out
+}
+
+fn unescape(field: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(field.len());
+    let mut iter = field.iter();
+    while let Some(b) = iter.next() {
+        if *b == b'\\' {
+            match iter.next() {
+                Some(b't') => out.push(b'\t'),
+                Some(b'n') => out.push(b'\n'),
+                Some(other) => out.push(*other),
+                None => {}
+            }
+        } else {
+            out.push(*b);
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fields_round_trip() {
+        for field in [
+            &b"plain"[..],
+            b"tab\there",
+            b"new\nline",
+            b"back\\slash\\t",
+            b"\xff\x00",
+        ] {
+            let escaped = escape(field);
+            assert!(!escaped.contains(&b'\t') && !escaped.contains(&b'\n'));
+            assert_eq!(unescape(&escaped), field);
+        }
+    }
+
+    #[test]
+    fn parses_ops() {
+        let f = |fields: &[&str]| {
+            fields
+                .iter()
+                .map(|s| s.as_bytes().to_vec())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            parse_op(&f(&["move", "/a/b", "id", "100644"])),
+            Some(Op::Move {
+                src: PathBuf::from("/a/b"),
+                name: "id".to_string(),
+                mode: 0o100644
+            })
+        );
+        assert_eq!(
+            parse_op(&f(&["restore", "id", "/a/b", "755"])),
+            Some(Op::Restore {
+                name: "id".to_string(),
+                dst: PathBuf::from("/a/b"),
+                mode: 0o755
+            })
+        );
+        assert_eq!(
+            parse_op(&f(&["purge", "/p"])),
+            Some(Op::Purge {
+                path: PathBuf::from("/p")
+            })
+        );
+        assert_eq!(
+            parse_op(&f(&["stage", "id", "/b"])),
+            Some(Op::Stage {
+                name: OsString::from("id"),
+                batch: PathBuf::from("/b")
+            })
+        );
+        assert_eq!(
+            parse_op(&f(&["unstage", "id", "/b"])),
+            Some(Op::Unstage {
+                name: OsString::from("id"),
+                batch: PathBuf::from("/b")
+            })
+        );
+        // names of old items needn't be UTF-8
+        assert_eq!(
+            parse_op(&[b"stage".to_vec(), b"caf\xe9".to_vec(), b"/b".to_vec()]),
+            Some(Op::Stage {
+                name: OsStr::from_bytes(b"caf\xe9").to_os_string(),
+                batch: PathBuf::from("/b")
+            })
+        );
+        assert_eq!(parse_op(&f(&["move", "/a", "id"])), None);
+        assert_eq!(parse_op(&f(&["move", "/a", "id", "9x"])), None);
+        assert_eq!(parse_op(&f(&["frobnicate", "/a"])), None);
+        assert_eq!(parse_op(&[]), None);
+    }
+
+    #[test]
+    fn pending_is_what_was_begun_but_not_ended() {
+        let home = std::env::temp_dir().join(format!("rmrs-journal-test-{}", std::process::id()));
+        std::fs::create_dir_all(&home).unwrap();
+        Journal::clear(&home).unwrap();
+        let done = Op::Purge {
+            path: PathBuf::from("/done"),
+        };
+        let open = Op::Move {
+            src: PathBuf::from("/odd\tname\n"),
+            name: "id".to_string(),
+            mode: 0o644,
+        };
+        let mut journal = Journal::open(&home).unwrap();
+        let seq = journal.begin(&done).unwrap();
+        journal.begin(&open).unwrap();
+        journal.end(seq).unwrap();
+        assert_eq!(Journal::pending(&home).unwrap(), vec![open]);
+        journal.commit().unwrap();
+        assert!(Journal::pending(&home).unwrap().is_empty());
+        std::fs::remove_dir_all(&home).unwrap();
+    }
+}
@@ -2,26 +2,75 @@ extern crate libc;
 use libc::time_t;
 use libc::{chmod, mode_t};
 use libc::{utimbuf, utime};
-use std::ffi::CString;
+use std::ffi::{CString, OsStr};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{DirBuilderExt, FileTypeExt, MetadataExt, PermissionsExt};
 use std::io;
 use std::{
     env,
-    fs::{self, File},
+    fs::{self, File, FileType},
     io::{stdin, Read, Write, stdout},
     path::{Path, PathBuf},
+    time::SystemTime,
 };
 pub mod unify;
+pub mod attrs;
+pub mod browse;
+pub mod display;
+pub mod doctor;
 pub mod error;
+pub mod history;
+pub mod info;
+pub mod interrupt;
+pub mod journal;
+pub mod lock;
+pub mod procfs;
+pub mod report;
+pub mod restore;
+pub mod search;
+pub mod staging;
+pub mod versions;
+use display::escape_path;
 use error::AppError;
+use info::TrashInfo;
+use journal::{Journal, Op};
 use path_absolutize::Absolutize;
 use regex::Regex;
 use serde::{Deserialize, Serialize};
-const CONFIG_FILE: &'static str = ".rmrs.toml";
+const CONFIG_FILE: &str = ".rmrs.toml";
 
 #[derive(Deserialize, Serialize)]
 pub struct Config {
     pub location: String,
     pub need_confirm_again: bool,
+    /// how file names are quoted on the terminal: "shell" or "c"
+    #[serde(default = "default_quoting_style")]
+    pub quoting_style: String,
+    /// what to do with a directory another process works in: "refuse" or "warn"
+    #[serde(default = "default_busy_directory")]
+    pub busy_directory: String,
+    /// what to do with a file another process holds open: "prompt", "warn" or "refuse"
+    #[serde(default = "default_open_files")]
+    pub open_files: String,
+    /// how long emptied items can still be brought back, e.g. "7d", "12h" or "0"
+    #[serde(default = "default_grace_period")]
+    pub grace_period: String,
+}
+
+fn default_quoting_style() -> String {
+    String::from("shell")
+}
+
+fn default_busy_directory() -> String {
+    String::from("refuse")
+}
+
+fn default_open_files() -> String {
+    String::from("warn")
+}
+
+fn default_grace_period() -> String {
+    String::from("7d")
 }
 /// A struct to store args
 #[derive(Debug)]
@@ -34,19 +83,23 @@ where
     pub c: bool,
     pub z: bool,
     pub b: bool,
+    pub fail_fast: bool,
+    pub atomic: bool,
 }
 
 impl<T> UserCommand<T>
 where
     T: AsRef<Path>,
 {
-    pub fn new(files: Vec<T>, f: bool, c: bool, z: bool, b: bool) -> UserCommand<T> {
+    pub fn new(files: Vec<T>, f: bool, c: bool, z: bool, b: bool, fail_fast: bool, atomic: bool) -> UserCommand<T> {
         Self {
             targets: files,
             f,
             c,
             z,
             b,
+            fail_fast,
+            atomic,
         }
     }
 }
@@ -65,8 +118,8 @@ pub fn update_file_mtime(file_path: &str, new_mtime: time_t) -> Result<(), Strin
     }
 }
 
-pub fn change_file_permissions(file_path: &str, mode: mode_t) -> Result<(), String> {
-    let c_file_path = CString::new(file_path).map_err(|_| "Failed to create CString")?;
+pub fn change_file_permissions(file_path: &Path, mode: mode_t) -> Result<(), String> {
+    let c_file_path = CString::new(file_path.as_os_str().as_bytes()).map_err(|_| "Failed to create CString")?;
 
     let result = unsafe { chmod(c_file_path.as_ptr(), mode) };
     if result == 0 {
@@ -76,26 +129,34 @@ pub fn change_file_permissions(file_path: &str, mode: mode_t) -> Result<(), Stri
     }
 }
 
+/// Total size of everything below `pb`. Symlinks count as themselves and are
+/// never followed, so a link can't pull an unrelated tree into the sum.
 pub fn get_dir_size(pb: &PathBuf) -> io::Result<u64> {
     let mut dir_size: u64 = 0;
     for p in fs::read_dir(pb)? {
-        let pt = p?.path();
-        if pt.is_file() {
-            dir_size = dir_size + pt.metadata()?.len();
-        } else if pt.is_dir() {
-            dir_size = dir_size + get_dir_size(&pt)?;
+        let p = p?;
+        let md = p.path().symlink_metadata()?;
+        if md.is_dir() {
+            dir_size += get_dir_size(&p.path())?;
+        } else {
+            dir_size += md.len();
         }
     }
     Ok(dir_size)
 }
 
+/// Seconds since the unix epoch, as records and batch ids count time
+pub fn now_timestamp() -> i64 {
+    SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs() as i64
+}
+
 pub fn friendly_size(size: u64) -> String {
     let units: Vec<&str> = vec!["Bytes", "KB", "MB", "GB", "TB", "PB"];
     let mut ptr: usize = 0;
     let mut fsize: f64 = size as f64;
     while fsize >= 1000.00 {
-        fsize = fsize / 1000.00;
-        ptr = ptr + 1;
+        fsize /= 1000.00;
+        ptr += 1;
     }
     format!("{:.2} {}", fsize, units[ptr])
 }
@@ -104,45 +165,180 @@ pub fn confirm() -> bool {
     if env::var("ca").unwrap().eq("false") {
         return true;
     }
-    print!("Are you sure? [Y/n] ");
+    ask("Are you sure?")
+}
+/// Ask `question` on the terminal, only `Y` counts as yes
+pub fn ask(question: &str) -> bool {
+    print!("{} [Y/n] ", question);
     stdout().flush().unwrap();
     let mut s: String = String::new();
     stdin().read_line(&mut s).unwrap();
-    return s.eq("Y\n");
+    s.eq("Y\n")
+}
+/// Create the trash home private to the user, or make an existing one private
+/// again. Trashed items keep their own permissions, so this directory is what
+/// keeps other users out.
+pub fn prepare_trash_home(trash_home: &Path) -> Result<(), AppError> {
+    if let Some(parent) = trash_home.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    if !trash_home.exists() {
+        fs::DirBuilder::new().mode(0o700).create(trash_home)?;
+    }
+    let md = fs::metadata(trash_home)?;
+    let euid = unsafe { libc::geteuid() };
+    if md.uid() != euid {
+        return Err(AppError {
+            code: -18,
+            message: format!(
+                "trash \"{}\" belongs to uid {}, not to you (uid {})",
+                trash_home.display(),
+                md.uid(),
+                euid
+            ),
+        });
+    }
+    if md.permissions().mode() & 0o077 != 0 {
+        fs::set_permissions(trash_home, fs::Permissions::from_mode(0o700))?;
+    }
+    fs::create_dir_all(trash_home.join("files"))?;
+    Ok(())
+}
+
+/// Rename without ever replacing an existing destination
+#[cfg(target_os = "linux")]
+pub fn rename_noreplace(from: &Path, to: &Path) -> io::Result<()> {
+    let c_from = CString::new(from.as_os_str().as_bytes())?;
+    let c_to = CString::new(to.as_os_str().as_bytes())?;
+    let ret = unsafe {
+        libc::syscall(
+            libc::SYS_renameat2,
+            libc::AT_FDCWD,
+            c_from.as_ptr(),
+            libc::AT_FDCWD,
+            c_to.as_ptr(),
+            libc::RENAME_NOREPLACE,
+        )
+    };
+    if ret == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn rename_noreplace(from: &Path, to: &Path) -> io::Result<()> {
+    if to.symlink_metadata().is_ok() {
+        return Err(io::ErrorKind::AlreadyExists.into());
+    }
+    fs::rename(from, to)
 }
-pub fn check_exist(f: String) -> Result<String, AppError> {
-    let to: PathBuf = PathBuf::from(env::var("tc").unwrap()).join(&f);
+
+/// Move `target` into the trash can under a fresh id and return that id.
+/// The move is left open in the journal, the caller ends it once the item
+/// has been recorded.
+pub fn move_into_trash(target: &Path, mode: u32, deleted_at: i64, journal: &mut Journal) -> Result<(String, u64), AppError> {
     let tc: PathBuf = PathBuf::from(env::var("tc").unwrap());
-    if to.exists() {
-        let idx = prefix(&f);
-        if let Some(new_name) = (2_u16..)
-            .map(|i| update_file_name(&f, idx, &i))
-            .find(|n| !tc.join(n).exists())
-        {
-            return Ok(new_name);
-        } else {
-            return Err(AppError {
-                code: -10,
-                message: "number exceed u16".to_string(),
-            });
+    loop {
+        let id = info::new_id(deleted_at)?;
+        let seq = journal.begin(&Op::Move {
+            src: target.to_path_buf(),
+            name: id.clone(),
+            mode,
+        })?;
+        match rename_noreplace(target, &tc.join(&id)) {
+            Ok(_) => return Ok((id, seq)),
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => journal.end(seq)?,
+            Err(e) => {
+                journal.end(seq)?;
+                return Err(e.into());
+            }
         }
     }
-    Ok(f)
 }
-fn update_file_name(ori: &str, idx: usize, i: &u16) -> String {
-    let mut dst: String = String::from(ori);
-    dst.insert_str(idx, format!("{}", i).as_str());
-    dst
+/// Write the record of an item that has just been renamed into the trash can,
+/// add it to the pending `.last` and return the line for the log
+#[allow(clippy::too_many_arguments)]
+pub fn finish_trash(
+    target: &Path,
+    to: &Path,
+    n: &str,
+    fty: &str,
+    st_mode_perms: u32,
+    last: &mut String,
+    now: &str,
+    user: &str,
+    timestamp_now: i64,
+) -> Result<String, AppError> {
+    let attrs = attrs::capture(to)?;
+    info::save(
+        &PathBuf::from(env::var("th").unwrap()),
+        &TrashInfo {
+            id: n.to_string(),
+            name: target.file_name().unwrap().to_os_string(),
+            path: target.to_path_buf(),
+            kind: fty.to_string(),
+            mode: st_mode_perms,
+            deleted_at: timestamp_now,
+            user: user.to_string(),
+            attrs: Some(attrs),
+            parent_mode: target
+                .parent()
+                .and_then(|p| p.symlink_metadata().ok())
+                .map(|md| md.permissions().mode()),
+        },
+    )?;
+    last.push_str(&format!("{}\n", n));
+    Ok(format!(
+        "{} {} deleted {} \"{}\" ${:o}$ => {}\n",
+        now,
+        user,
+        fty,
+        escape_path(target),
+        st_mode_perms%512,
+        n
+    ))
 }
-fn prefix(f: &str) -> usize {
-    for (idx, c) in f.chars().enumerate() {
-        if idx != 0 && c == '.' {
-            return idx;
-        }
+
+/// Replace `.last` with the records of this batch. A batch that trashed
+/// nothing leaves the previous one undoable.
+pub fn write_last(last: &str) -> Result<(), AppError> {
+    if last.is_empty() {
+        return Ok(());
+    }
+    let th = PathBuf::from(env::var("th").unwrap());
+    let path_tmp = th.join(".last.tmp");
+    let mut file_tmp = File::create(&path_tmp)?;
+    file_tmp.write_all(last.as_bytes())?;
+    file_tmp.sync_all()?;
+    fs::rename(&path_tmp, th.join(".last"))?;
+    Ok(())
+}
+
+/// Drop the items with the given ids from `.last` once they are out of the
+/// trash can, so that `-z` doesn't trip over them
+pub fn forget_in_last(ids: &[String]) -> Result<(), AppError> {
+    let th = PathBuf::from(env::var("th").unwrap());
+    let tc = PathBuf::from(env::var("tc").unwrap());
+    let path_last = th.join(".last");
+    if ids.is_empty() || !path_last.exists() {
+        return Ok(());
+    }
+    let kept: String = fs::read_to_string(&path_last)?
+        .lines()
+        .filter(|l| !ids.iter().any(|r| l == r || l.starts_with(&format!("{} >> ", tc.join(r).display()))))
+        .map(|l| format!("{}\n", l))
+        .collect();
+    if kept.is_empty() {
+        fs::remove_file(&path_last)?;
+    } else {
+        write_last(&kept)?;
     }
-    f.len()
+    Ok(())
 }
-pub fn conv_to_abs(src: Vec<&str>) -> Vec<PathBuf> {
+
+pub fn conv_to_abs(src: Vec<&OsStr>) -> Vec<PathBuf> {
     let mut abs: Vec<PathBuf> = Vec::new();
     for s in src {
         let t: PathBuf = PathBuf::from(s).absolutize().unwrap().into_owned();
@@ -154,24 +350,61 @@ pub fn is_valid_path(p: &str) -> bool {
     let re = Regex::new(r"^/[/?\.?\w]+\w$").unwrap();
     match re.find(p) {
         Some(m) => {
-            return m.as_str().eq(p);
+            m.as_str().eq(p)
         }
         None => {
-            return false;
+            false
         }
     }
 }
-pub fn get_type(t: &PathBuf) -> String {
-    if t.is_dir() {
-        return "directory".to_string();
-    } else if t.is_file(){
-        return "file".to_string();   
+/// What kind of file `t` is, without following a symlink
+pub fn get_type(t: &Path) -> String {
+    match t.symlink_metadata() {
+        Ok(md) => kind_of(&md.file_type()).to_string(),
+        Err(_) => "undefined type".to_string(),
+    }
+}
+
+pub fn kind_of(ft: &FileType) -> &'static str {
+    if ft.is_symlink() {
+        "symbolic link"
+    } else if ft.is_dir() {
+        "directory"
+    } else if ft.is_file() {
+        "file"
+    } else if ft.is_fifo() {
+        "fifo"
+    } else if ft.is_socket() {
+        "socket"
+    } else if ft.is_char_device() {
+        "character device"
+    } else if ft.is_block_device() {
+        "block device"
+    } else {
+        "undefined type"
+    }
+}
+
+/// The one-letter file type `ls -l` shows
+pub fn type_char(ft: &FileType) -> char {
+    if ft.is_symlink() {
+        'l'
+    } else if ft.is_dir() {
+        'd'
+    } else if ft.is_fifo() {
+        'p'
+    } else if ft.is_socket() {
+        's'
+    } else if ft.is_char_device() {
+        'c'
+    } else if ft.is_block_device() {
+        'b'
     } else {
-        return "undefined type".to_string();
+        '-'
     }
 }
 
-pub fn proc_toml() -> Result<(String, bool), AppError> {
+pub fn proc_toml() -> Result<Config, AppError> {
     let mut p = PathBuf::new();
     let envv = unify::ENVV {
         #[cfg(not(target_os="windows"))]
@@ -191,7 +424,7 @@ pub fn proc_toml() -> Result<(String, bool), AppError> {
         let mut content = String::new();
         conf.read_to_string(&mut content)?;
         if let Ok(config) = toml::from_str::<Config>(&content) {
-            return Ok((config.location, config.need_confirm_again));
+            Ok(config)
         } else {
             fs::remove_file(&p)?;
             Err(AppError {
@@ -214,6 +447,10 @@ pub fn proc_toml() -> Result<(String, bool), AppError> {
         let mut config: Config = Config {
             location: String::from(""),
             need_confirm_again: true,
+            quoting_style: default_quoting_style(),
+            busy_directory: default_busy_directory(),
+            open_files: default_open_files(),
+            grace_period: default_grace_period(),
         };
         if is_valid_path(&user_input) {
             config.location = user_input;
@@ -223,6 +460,6 @@ pub fn proc_toml() -> Result<(String, bool), AppError> {
         let content = toml::to_string(&config)?;
         conf.write_all(content.as_bytes())?;
         conf.flush()?;
-        return Ok((config.location, config.need_confirm_again));
+        Ok(config)
     }
 }
@@ -0,0 +1,92 @@
+use crate::error::AppError;
+use libc::{flock, LOCK_EX, LOCK_NB, LOCK_SH, LOCK_UN};
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, Read, Seek, SeekFrom, Write},
+    os::unix::io::AsRawFd,
+    path::Path,
+    thread,
+    time::{Duration, Instant},
+};
+
+/// How long to wait for another rmrs before giving up
+pub const LOCK_TIMEOUT: Duration = Duration::from_secs(10);
+const LOCK_FILE: &str = ".lock";
+
+/// Advisory lock on the trash home, held for the whole lifetime of a command
+/// so that name selection, `.last` and the log are never touched by two
+/// processes at once. The lock is released when the guard is dropped.
+pub struct TrashLock {
+    file: File,
+    exclusive: bool,
+}
+
+impl TrashLock {
+    /// Take the lock exclusively, for commands that change the trash
+    pub fn exclusive(trash_home: &Path) -> Result<TrashLock, AppError> {
+        Self::acquire(trash_home, LOCK_EX, LOCK_TIMEOUT)
+    }
+
+    /// Take the lock shared, for commands that only read the trash
+    pub fn shared(trash_home: &Path) -> Result<TrashLock, AppError> {
+        Self::acquire(trash_home, LOCK_SH, LOCK_TIMEOUT)
+    }
+
+    fn acquire(trash_home: &Path, operation: i32, timeout: Duration) -> Result<TrashLock, AppError> {
+        let mut file = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .write(true)
+            .truncate(false)
+            .open(trash_home.join(LOCK_FILE))?;
+        let started = Instant::now();
+        loop {
+            let ret = unsafe { flock(file.as_raw_fd(), operation | LOCK_NB) };
+            if ret == 0 {
+                break;
+            }
+            let err = io::Error::last_os_error();
+            if err.kind() != io::ErrorKind::WouldBlock {
+                return Err(err.into());
+            }
+            if started.elapsed() >= timeout {
+                let mut holder = String::new();
+                file.read_to_string(&mut holder)?;
+                let holder = match holder.trim() {
+                    "" => "another rmrs".to_string(),
+                    pid => format!("another rmrs (pid {})", pid),
+                };
+                return Err(AppError {
+                    code: -12,
+                    message: format!(
+                        "{} is using the trash, gave up after {}s",
+                        holder,
+                        timeout.as_secs()
+                    ),
+                });
+            }
+            thread::sleep(Duration::from_millis(100));
+        }
+        if operation == LOCK_EX {
+            file.set_len(0)?;
+            file.seek(SeekFrom::Start(0))?;
+            write!(file, "{}", std::process::id())?;
+            file.flush()?;
+        }
+        Ok(Self {
+            file,
+            exclusive: operation == LOCK_EX,
+        })
+    }
+}
+
+impl Drop for TrashLock {
+    fn drop(&mut self) {
+        if self.exclusive {
+            let _ = self.file.set_len(0);
+        }
+        unsafe {
+            flock(self.file.as_raw_fd(), LOCK_UN);
+        }
+    }
+}
@@ -1,31 +1,44 @@
-use clap::{self, command, Arg, ArgAction};
-use regex::Regex;
-use rmrs::{check_exist, confirm, update_file_mtime, change_file_permissions, get_dir_size, friendly_size};
-use rmrs::{conv_to_abs, error::AppError, get_type, proc_toml, UserCommand};
-use std::fs::read_dir;
-use std::os::unix::fs::PermissionsExt;
-use std::time::SystemTime;
+use clap::{self, command, Arg, ArgAction, Command};
+use rmrs::{ask, confirm};
+use rmrs::{conv_to_abs, prepare_trash_home, error::AppError, get_type, proc_toml, UserCommand};
+use rmrs::browse::{self, show_trash};
+use rmrs::display::{escape, escape_path, quote, quote_path};
+use rmrs::doctor::{doctor, recover};
+use rmrs::history::parse_last_line;
+use rmrs::info::{self, TrashInfo};
+use rmrs::interrupt::{self, interrupted_error};
+use rmrs::journal::{Journal, Op};
+use rmrs::lock::TrashLock;
+use rmrs::procfs;
+use rmrs::report::{BatchReport, Outcome};
+use rmrs::restore::{self, put_back_attrs};
+use rmrs::search;
+use rmrs::staging;
+use rmrs::versions;
+use rmrs::{finish_trash, forget_in_last, move_into_trash, now_timestamp, rename_noreplace, write_last};
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::ffi::{OsStr, OsString};
 use std::{
     env::{self},
-    fs::{self, remove_dir_all, remove_file, rename, File, OpenOptions},
-    io::{BufRead, BufReader, Write},
+    fs::{self, remove_dir_all, remove_file, File, OpenOptions},
+    io::{stdin, BufRead, BufReader, IsTerminal, Write},
     path::{Path, PathBuf},
 };
 use time as Dime;
 use Dime::{format_description, macros::offset};
 
-#[allow(unused_assignments)]
 fn main() -> Result<(), AppError> {
-    let mut trash_home = String::new();
-    let mut confirm_again = true;
-    (trash_home, confirm_again)  = proc_toml()?;
+    let config = proc_toml()?;
+    let trash_home = config.location;
     let trash_can = Path::new(trash_home.as_str()).join("files");
-    if !trash_can.exists() {
-        fs::create_dir_all(&trash_can)?;
-    }
+    prepare_trash_home(Path::new(trash_home.as_str()))?;
     env::set_var("th", trash_home);
     env::set_var("tc", trash_can);
-    env::set_var("ca", confirm_again.to_string());
+    env::set_var("ca", config.need_confirm_again.to_string());
+    env::set_var("qs", config.quoting_style);
+    env::set_var("bd", config.busy_directory);
+    env::set_var("oh", config.open_files);
+    env::set_var("gp", staging::parse_grace(&config.grace_period)?.to_string());
     run()
 }
 
@@ -46,8 +59,9 @@ author: {author-with-newline}
         .arg(
             Arg::new("targets")
                 .action(ArgAction::Append)
+                .value_parser(clap::value_parser!(OsString))
                 .required(false)
-                .help("file(s) or dir(s) or both of them you want to delete"),
+                .help("file(s) or dir(s) or both of them you want to delete; put them after -- (or write ./name) when the first one is named like a command"),
         )
         // .arg(
         //     Arg::new("location")
@@ -73,6 +87,14 @@ author: {author-with-newline}
                 .long("clear")
                 .help("clear trash"),
         )
+        .arg(
+            Arg::new("now")
+                .action(ArgAction::SetTrue)
+                .required(false)
+                .long("now")
+                .requires("clear")
+                .help("delete what is cleared for good right away instead of after the grace period"),
+        )
         .arg(
             Arg::new("regret")
                 .action(ArgAction::SetTrue)
@@ -89,19 +111,285 @@ author: {author-with-newline}
             .long("browse")
             .help("show trash info"),
         )
+        .arg(
+            Arg::new("fail-fast")
+                .action(ArgAction::SetTrue)
+                .required(false)
+                .long("fail-fast")
+                .help("stop at the first target that can't be deleted"),
+        )
+        .arg(
+            Arg::new("atomic")
+                .action(ArgAction::SetTrue)
+                .required(false)
+                .long("atomic")
+                .conflicts_with("forever")
+                .help("trash all targets or none of them"),
+        )
+        .subcommand(
+            Command::new("doctor")
+                .about("check the trash for items without records and broken records")
+                .arg(
+                    Arg::new("fix")
+                        .action(ArgAction::SetTrue)
+                        .required(false)
+                        .long("fix")
+                        .help("repair what can be repaired"),
+                ),
+        )
+        .subcommand(
+            Command::new("recover")
+                .about("rebuild the records of items in the trash from the log")
+                .arg(
+                    Arg::new("dry-run")
+                        .action(ArgAction::SetTrue)
+                        .required(false)
+                        .long("dry-run")
+                        .help("only show which records would be rebuilt"),
+                ),
+        )
+        .subcommand(
+            Command::new("ls")
+                .about("list what is inside a trashed directory")
+                .arg(
+                    Arg::new("item")
+                        .required_unless_present("deleted")
+                        .help("id of the item, or an unambiguous prefix of it"),
+                )
+                .arg(
+                    Arg::new("subpath")
+                        .required(false)
+                        .value_parser(clap::value_parser!(PathBuf))
+                        .help("directory inside the item to list"),
+                )
+                .arg(
+                    Arg::new("deleted")
+                        .action(ArgAction::Set)
+                        .required(false)
+                        .long("deleted")
+                        .conflicts_with_all(["item", "subpath"])
+                        .value_parser(clap::value_parser!(OsString))
+                        .help("list a directory together with what was deleted from it"),
+                ),
+        )
+        .subcommand(
+            Command::new("tree")
+                .about("show everything inside a trashed directory as a tree")
+                .arg(Arg::new("item").required(true).help("id of the item, or an unambiguous prefix of it"))
+                .arg(
+                    Arg::new("subpath")
+                        .required(false)
+                        .value_parser(clap::value_parser!(PathBuf))
+                        .help("directory inside the item to start from"),
+                ),
+        )
+        .subcommand(
+            Command::new("cat")
+                .about("print a trashed file")
+                .arg(
+                    Arg::new("item")
+                        .required(true)
+                        .value_parser(clap::value_parser!(OsString))
+                        .help("id of the item, optionally followed by /<path inside it>"),
+                ),
+        )
+        .subcommand(
+            Command::new("diff")
+                .about("compare a trashed file or directory with its original location or another item")
+                .arg(
+                    Arg::new("item")
+                        .required(true)
+                        .value_parser(clap::value_parser!(OsString))
+                        .help("id of the item, optionally followed by /<path inside it>"),
+                )
+                .arg(
+                    Arg::new("other")
+                        .required(false)
+                        .value_parser(clap::value_parser!(OsString))
+                        .help("another item, or a path; the original location if left out"),
+                ),
+        )
+        .subcommand(
+            Command::new("find")
+                .about("search the trash by name and original path, including inside trashed directories")
+                .arg(
+                    Arg::new("pattern")
+                        .required(true)
+                        .help("glob matched against names, or against paths if it contains a /"),
+                )
+                .arg(
+                    Arg::new("regex")
+                        .action(ArgAction::SetTrue)
+                        .required(false)
+                        .long("regex")
+                        .help("the pattern is a regex searched for in original paths"),
+                ),
+        )
+        .subcommand(
+            Command::new("grep")
+                .about("search the content of trashed files, skipping binary ones")
+                .arg(Arg::new("regex").required(true).help("regex searched for in every line"))
+                .arg(
+                    Arg::new("ignore-case")
+                        .action(ArgAction::SetTrue)
+                        .required(false)
+                        .short('i')
+                        .long("ignore-case")
+                        .help("match upper and lower case alike"),
+                ),
+        )
+        .subcommand(
+            Command::new("versions")
+                .about("list the items deleted from the same path, newest first")
+                .arg(
+                    Arg::new("path")
+                        .required(false)
+                        .value_parser(clap::value_parser!(OsString))
+                        .help("original path; every path deleted more than once if left out"),
+                )
+                .arg(
+                    Arg::new("restore")
+                        .action(ArgAction::Set)
+                        .required(false)
+                        .long("restore")
+                        .requires("path")
+                        .value_parser(clap::value_parser!(usize))
+                        .help("restore this version, 1 being the newest"),
+                )
+                .arg(
+                    Arg::new("on-conflict")
+                        .action(ArgAction::Set)
+                        .required(false)
+                        .long("on-conflict")
+                        .requires("restore")
+                        .value_parser(restore::Conflict::NAMES)
+                        .default_value("skip")
+                        .help("what to do when the original path is occupied"),
+                )
+                .arg(
+                    Arg::new("keep")
+                        .action(ArgAction::Set)
+                        .required(false)
+                        .long("keep")
+                        .conflicts_with("restore")
+                        .value_parser(clap::value_parser!(usize))
+                        .help("remove all but the newest N versions from the trash"),
+                )
+                .arg(
+                    Arg::new("now")
+                        .action(ArgAction::SetTrue)
+                        .required(false)
+                        .long("now")
+                        .requires("keep")
+                        .help("delete them for good right away instead of after the grace period"),
+                ),
+        )
+        .subcommand(
+            Command::new("unclear")
+                .about("bring back what was cleared or pruned, the latest batch if none is given")
+                .arg(Arg::new("batch").required(false).help("id of the purged batch, or an unambiguous prefix of it"))
+                .arg(
+                    Arg::new("list")
+                        .action(ArgAction::SetTrue)
+                        .required(false)
+                        .long("list")
+                        .conflicts_with("batch")
+                        .help("list the purged batches waiting to be deleted"),
+                ),
+        )
+        .subcommand(
+            Command::new("restore")
+                .about("put items back from the trash, the last batch if none are given")
+                .arg(
+                    Arg::new("items")
+                        .action(ArgAction::Append)
+                        .required(false)
+                        .value_parser(clap::value_parser!(OsString))
+                        .help("ids of the items, or unambiguous prefixes of them, optionally followed by /<path inside the item>"),
+                )
+                .arg(
+                    Arg::new("to")
+                        .action(ArgAction::Set)
+                        .required(false)
+                        .long("to")
+                        .value_parser(clap::value_parser!(OsString))
+                        .help("restore into this directory instead of the original location"),
+                )
+                .arg(
+                    Arg::new("parents")
+                        .action(ArgAction::SetTrue)
+                        .required(false)
+                        .long("parents")
+                        .help("recreate missing parent directories"),
+                )
+                .arg(
+                    Arg::new("on-conflict")
+                        .action(ArgAction::Set)
+                        .required(false)
+                        .long("on-conflict")
+                        .value_parser(restore::Conflict::NAMES)
+                        .default_value("skip")
+                        .help("what to do when the destination is occupied"),
+                )
+                .arg(
+                    Arg::new("path")
+                        .action(ArgAction::Set)
+                        .required(false)
+                        .long("path")
+                        .value_parser(clap::value_parser!(PathBuf))
+                        .help("restore only this file or directory inside a trashed directory"),
+                )
+                .arg(
+                    Arg::new("under")
+                        .action(ArgAction::Set)
+                        .required(false)
+                        .long("under")
+                        .conflicts_with_all(["items", "path"])
+                        .value_parser(clap::value_parser!(OsString))
+                        .help("restore everything that was deleted from this directory"),
+                )
+                .arg(
+                    Arg::new("since")
+                        .action(ArgAction::Set)
+                        .required(false)
+                        .long("since")
+                        .conflicts_with_all(["items", "path"])
+                        .help("restore what was deleted at or after this time"),
+                )
+                .arg(
+                    Arg::new("until")
+                        .action(ArgAction::Set)
+                        .required(false)
+                        .long("until")
+                        .conflicts_with_all(["items", "path"])
+                        .help("restore what was deleted at or before this time"),
+                ),
+        )
         .get_matches();
     let args = matches
-        .get_many::<String>("targets")
+        .get_many::<OsString>("targets")
         .unwrap_or_default()
-        .map(|v| v.as_str())
+        .map(|v| v.as_os_str())
         .collect::<Vec<_>>();
     let f = matches.get_flag("forever");
     let c = matches.get_flag("clear");
     let z = matches.get_flag("regret");
     let b = matches.get_flag("browse");
+    let fail_fast = matches.get_flag("fail-fast");
+    let atomic = matches.get_flag("atomic");
     let vec_target_abs = conv_to_abs(args);
-    let user_args = UserCommand::new(vec_target_abs, f, c, z, b);
-    let path_log: PathBuf = PathBuf::from(env::var("th").unwrap()).join("log");
+    let user_args = UserCommand::new(vec_target_abs, f, c, z, b, fail_fast, atomic);
+    let trash_home = PathBuf::from(env::var("th").unwrap());
+    // 只读的命令可以并行
+    let read_only = user_args.b
+        || matches!(matches.subcommand_name(), Some("ls" | "tree" | "cat" | "diff" | "find" | "grep"))
+        || matches!(matches.subcommand(), Some(("versions", sub)) if !sub.contains_id("restore") && !sub.contains_id("keep"));
+    let _lock = if read_only {
+        TrashLock::shared(&trash_home)?
+    } else {
+        TrashLock::exclusive(&trash_home)?
+    };
+    let path_log: PathBuf = trash_home.join("log");
     let file_log = OpenOptions::new()
         .append(true)
         .create(true)
@@ -113,179 +401,784 @@ author: {author-with-newline}
             "[year]-[month]-[day] [hour]:[minute]:[second] [offset_hour \
          sign:mandatory]:[offset_minute]:[offset_second]",
         )?)?;
+    if user_args.b {
+        return show_trash();
+    }
+    if let Some(("ls", sub)) = matches.subcommand() {
+        if let Some(dir) = sub.get_one::<OsString>("deleted") {
+            return browse::ls_deleted(&conv_to_abs(vec![dir.as_os_str()]).remove(0));
+        }
+    }
+    if let Some((name @ ("ls" | "tree"), sub)) = matches.subcommand() {
+        let record = info::resolve(&trash_home, sub.get_one::<String>("item").unwrap())?;
+        let subpath = sub.get_one::<PathBuf>("subpath").map(|p| p.as_path());
+        return if name == "ls" {
+            browse::ls(&record, subpath)
+        } else {
+            browse::tree(&record, subpath)
+        };
+    }
+    if let Some(("find", sub)) = matches.subcommand() {
+        return search::find(sub.get_one::<String>("pattern").unwrap(), sub.get_flag("regex"));
+    }
+    if let Some(("grep", sub)) = matches.subcommand() {
+        return search::grep(sub.get_one::<String>("regex").unwrap(), sub.get_flag("ignore-case"));
+    }
+    if let Some(("recover", sub)) = matches.subcommand() {
+        return recover(&file_log, &time_local, sub.get_flag("dry-run"));
+    }
+    if let Some(("versions", sub)) = matches.subcommand() {
+        if read_only {
+            let path = sub.get_one::<OsString>("path").map(|p| conv_to_abs(vec![p.as_os_str()]).remove(0));
+            return versions::show(&versions::groups(path.as_deref())?);
+        }
+    }
+    if let Some((name @ ("cat" | "diff"), sub)) = matches.subcommand() {
+        let (item, subpath) = browse::parse_spec(sub.get_one::<OsString>("item").unwrap());
+        let record = info::resolve(&trash_home, &item)?;
+        if name == "cat" {
+            return browse::cat(&record, subpath.as_deref());
+        }
+        // 先当作回收站里的项目，找不到再当作路径
+        let other = match sub.get_one::<OsString>("other") {
+            Some(other) => {
+                let (other_item, other_sub) = browse::parse_spec(other);
+                match info::resolve(&trash_home, &other_item) {
+                    Ok(other_record) => {
+                        let pb = match &other_sub {
+                            Some(p) => other_record.locate(p).map_err(|message| AppError { code: -16, message })?,
+                            None => other_record.stored(),
+                        };
+                        Some((pb, escape_path(Path::new(other))))
+                    }
+                    Err(_) => {
+                        let pb = conv_to_abs(vec![other.as_os_str()]).remove(0);
+                        let label = escape_path(&pb);
+                        Some((pb, label))
+                    }
+                }
+            }
+            None => None,
+        };
+        return browse::diff(&record, subpath.as_deref(), other);
+    }
+    recover_journal(&file_log, &time_local)?;
+    let mut journal = Journal::open(&trash_home)?;
+    staging::expire(&file_log, &time_local, &mut journal)?;
+    if let Some(("unclear", sub)) = matches.subcommand() {
+        if sub.get_flag("list") {
+            return staging::show();
+        }
+        let batch = staging::resolve(sub.get_one::<String>("batch").map(|b| b.as_str()))?;
+        return staging::unclear(&batch, &file_log, &time_local, &mut journal);
+    }
+    if let Some(("doctor", sub)) = matches.subcommand() {
+        return doctor(&file_log, &time_local, sub.get_flag("fix"));
+    }
+    if let Some(("versions", sub)) = matches.subcommand() {
+        let path = sub.get_one::<OsString>("path").map(|p| conv_to_abs(vec![p.as_os_str()]).remove(0));
+        let groups = versions::groups(path.as_deref())?;
+        if let Some(n) = sub.get_one::<usize>("restore") {
+            let chosen = groups.first().and_then(|(_, v)| v.get(n.wrapping_sub(1))).ok_or(AppError {
+                code: -16,
+                message: format!("there is no version {} of {}", n, quote_path(path.as_deref().unwrap())),
+            })?;
+            let options = restore::Options {
+                on_conflict: restore::Conflict::parse(sub.get_one::<String>("on-conflict").unwrap()).unwrap_or_default(),
+                ..Default::default()
+            };
+            return restore_items(vec![OsString::from(&chosen.id)], &options, &file_log, &time_local, &mut journal);
+        }
+        let keep = *sub.get_one::<usize>("keep").unwrap();
+        let doomed: usize = groups.iter().map(|(_, v)| v.len().saturating_sub(keep)).sum();
+        if doomed == 0 {
+            println!("nothing to remove");
+            return Ok(());
+        }
+        versions::show(&groups)?;
+        let for_good = sub.get_flag("now") || staging::grace() == 0;
+        if for_good {
+            println!("{} old versions will be removed for good", doomed);
+        } else {
+            println!("{} old versions will be removed", doomed);
+        }
+        if confirm() {
+            let batch = staging::create()?;
+            let pruned = versions::prune(&groups, keep, &batch, &file_log, &time_local, &mut journal)?;
+            if for_good {
+                staging::delete(&batch, &file_log, &time_local, &mut journal)?;
+                println!("removed {} old versions for good", pruned);
+            } else {
+                println!(
+                    "removed {} old versions, `rmrs unclear {}` brings them back until {}",
+                    pruned,
+                    batch.id,
+                    info::local_time(batch.expires_at())
+                );
+            }
+        }
+        return Ok(());
+    }
+    if let Some(("restore", sub)) = matches.subcommand() {
+        let items: Vec<OsString> = sub.get_many::<OsString>("items").unwrap_or_default().cloned().collect();
+        let options = restore::Options {
+            to: sub.get_one::<OsString>("to").map(|to| conv_to_abs(vec![to.as_os_str()]).remove(0)),
+            parents: sub.get_flag("parents"),
+            on_conflict: restore::Conflict::parse(sub.get_one::<String>("on-conflict").unwrap()).unwrap_or_default(),
+            path: sub.get_one::<PathBuf>("path").cloned(),
+        };
+        if options.path.is_some() && items.len() != 1 {
+            return Err(AppError {
+                code: -16,
+                message: "--path needs exactly one item".to_string(),
+            });
+        }
+        if ["under", "since", "until"].iter().any(|f| sub.contains_id(f)) {
+            return restore_selected(sub, &options, &file_log, &time_local, &mut journal);
+        }
+        return restore_items(items, &options, &file_log, &time_local, &mut journal);
+    }
     if user_args.z {
-        regret(&file_log, &time_local)
-    } else if user_args.b {
-        show_trash()
+        regret(&file_log, &time_local, &mut journal)
     } else if !user_args.targets.is_empty() {
-        if user_args.f {
-            move_to_trash(user_args.targets, &file_log, &time_local, true)
+        if user_args.atomic {
+            move_to_trash_atomic(user_args.targets, &file_log, &time_local, &mut journal)
         } else {
-            move_to_trash(user_args.targets, &file_log, &time_local, false)
+            move_to_trash(user_args.targets, &file_log, &time_local, user_args.f, user_args.fail_fast, &mut journal)
         }
     } else if user_args.c {
-        clear(&file_log, &time_local)
+        clear(&file_log, &time_local, matches.get_flag("now"), &mut journal)
     } else {
         Ok(())
     }
 }
 
-fn show_trash() -> Result<(), AppError>{
-    let trash_can = PathBuf::from(env::var("tc").unwrap());
-    let mut total_size: u64 = 0;
-    for entry in read_dir(trash_can)? {
-        let mut size: u64 = 0;
-        let pb = entry?.path();
-        let md = &pb.metadata().unwrap();
-        if pb.is_file() {
-            size = md.len();
-        } else if pb.is_dir() {
-            size = get_dir_size(&pb).unwrap();
-        }
-        total_size = total_size + size;
-        let st_mode_perms = md.permissions().mode() % 512;
-        let name = pb.file_name().unwrap().to_str().unwrap();
-        println!("${:0<3o} {} {}", st_mode_perms, name, friendly_size(size));
-    }
-    Ok(())
-}
-
 fn move_to_trash(
     targets: Vec<PathBuf>,
-    mut log: &File,
+    log: &File,
     now: &str,
     permanently: bool,
+    fail_fast: bool,
+    journal: &mut Journal,
 ) -> Result<(), AppError> {
-    let path_last = PathBuf::from(env::var("th").unwrap()).join(".last");
-    let mut file_last = OpenOptions::new()
-        .create(true)
-        .write(true)
-        .open(path_last)?;
-    // let to: PathBuf = PathBuf::from(env::var("tc").unwrap());
-    #[allow(unused_assignments)]
-    let mut info_log = String::new();
-    #[allow(unused_assignments)]
-    let mut info_last = String::new();
+    interrupt::install();
+    let mut last = String::new();
     let user: String = env::var("USER").unwrap_or("default".to_string());
-    let timestamp_now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs() as i64;
+    let timestamp_now = now_timestamp();
+    let mut report = BatchReport::new();
     for target in targets {
-        if target.is_dir() && env::var("PWD")?.starts_with(target.to_str().unwrap()) {
-            info_log = format!("{} {} tried to delete directory \"{}\" while I refused: Forbid to delete ancestor\n", now, &user, target.display());
-            log.write_all(info_log.as_bytes())?;
+        if (fail_fast && report.failed() > 0) || interrupt::interrupted() {
+            report.push(target, Outcome::Skipped);
             continue;
         }
-        if permanently {
-            let fty = get_type(&target);
-            match remove_file(&target) {
-                Ok(_) => {
-                    info_log = format!(
-                        "{} {} permanently deleted {} \"{}\"\n",
+        let outcome = if permanently {
+            delete_one(&target, log, now, &user)
+        } else {
+            trash_one(&target, log, &mut last, now, &user, timestamp_now, journal)
+        };
+        let outcome = match outcome {
+            Ok(outcome) => outcome,
+            Err(e) => {
+                // what this batch moved so far stays undoable
+                write_last(&last)?;
+                return Err(e);
+            }
+        };
+        report.push(target, outcome);
+    }
+    write_last(&last)?;
+    journal.commit()?;
+    report.print_summary(if permanently { "deleted" } else { "trashed" });
+    if interrupt::interrupted() {
+        return Err(interrupted_error());
+    }
+    report.into_result()
+}
+
+/// Why `target` must not be deleted: it is the working directory of rmrs or,
+/// unless `busy_directory` is "warn", of another process, or an ancestor of one
+fn cwd_problem(target: &Path) -> Option<String> {
+    // a symlink to a directory can go, its target stays where it is
+    let is_dir = target.symlink_metadata().map(|md| md.is_dir()).unwrap_or(false);
+    if !is_dir {
+        return None;
+    }
+    let target = procfs::canonical(target);
+    // getcwd fails if the working directory is gone already
+    if env::current_dir().is_ok_and(|cwd| cwd.starts_with(&target)) {
+        return Some("Forbid to delete ancestor".to_string());
+    }
+    let users = procfs::cwd_users(&target);
+    if users.is_empty() {
+        return None;
+    }
+    let holders = users.iter().map(|p| p.to_string()).collect::<Vec<_>>().join(", ");
+    if env::var("bd").as_deref() == Ok("warn") {
+        eprintln!("warning: {} is in use as working directory of {}", quote_path(&target), holders);
+        return None;
+    }
+    Some(format!("Working directory of {}", holders))
+}
+
+/// Why `target` must not be deleted: another process holds it, or something
+/// beneath it, open. `open_files` decides between asking, warning and refusing.
+fn open_problem(target: &Path) -> Option<String> {
+    let holders = procfs::holders(&procfs::canonical(target));
+    if holders.is_empty() {
+        return None;
+    }
+    let holders = holders.iter().map(|p| p.to_string()).collect::<Vec<_>>().join(", ");
+    match env::var("oh").as_deref() {
+        Ok("refuse") => Some(format!("Held open by {}", holders)),
+        Ok("prompt") => {
+            let question = format!("{} is held open by {}, delete it anyway?", quote_path(target), holders);
+            // nobody to answer without a terminal
+            if stdin().is_terminal() && ask(&question) {
+                None
+            } else {
+                Some(format!("Held open by {}", holders))
+            }
+        }
+        _ => {
+            eprintln!("warning: {} is held open by {}", quote_path(target), holders);
+            None
+        }
+    }
+}
+
+/// Refuse to delete a working directory, one of its ancestors or files in use
+fn refuse_busy(target: &Path, mut log: &File, now: &str, user: &str) -> Result<Option<Outcome>, AppError> {
+    if let Some(reason) = cwd_problem(target).or_else(|| open_problem(target)) {
+        let info_log = format!(
+            "{} {} tried to delete {} \"{}\" while I refused: {}\n",
+            now,
+            user,
+            get_type(target),
+            escape_path(target),
+            reason
+        );
+        log.write_all(info_log.as_bytes())?;
+        return Ok(Some(Outcome::Failed(reason)));
+    }
+    Ok(None)
+}
+
+/// Remove one target from disc. `Err` is reserved for failures that
+/// should stop the whole batch, e.g. the log being unwritable.
+fn delete_one(target: &Path, mut log: &File, now: &str, user: &str) -> Result<Outcome, AppError> {
+    if let Some(refused) = refuse_busy(target, log, now, user)? {
+        return Ok(refused);
+    }
+    let fty = get_type(target);
+    #[allow(unused_assignments)]
+    let mut info_log = String::new();
+    let outcome = match remove_file(target) {
+        Ok(_) => {
+            info_log = format!(
+                "{} {} permanently deleted {} \"{}\"\n",
+                now,
+                user,
+                fty,
+                escape_path(target),
+            );
+            Outcome::Deleted
+        }
+        Err(e) => {
+            info_log = format!(
+                "{} {} tried to permanently delete {} \"{}\" while an error occured: {} \n",
+                now,
+                user,
+                fty,
+                escape_path(target),
+                e
+            );
+            Outcome::Failed(e.to_string())
+        }
+    };
+    log.write_all(info_log.as_bytes())?;
+    Ok(outcome)
+}
+
+/// Move one target into the trash can, see [`delete_one`] for the meaning of `Err`
+fn trash_one(
+    target: &Path,
+    mut log: &File,
+    last: &mut String,
+    now: &str,
+    user: &str,
+    timestamp_now: i64,
+    journal: &mut Journal,
+) -> Result<Outcome, AppError> {
+    if let Some(refused) = refuse_busy(target, log, now, user)? {
+        return Ok(refused);
+    }
+    let fty = get_type(target);
+    #[allow(unused_assignments)]
+    let mut info_log = String::new();
+    let moved = target
+        .symlink_metadata()
+        .map_err(AppError::from)
+        .and_then(|md| move_into_trash(target, md.permissions().mode(), timestamp_now, journal).map(|(n, seq)| (n, seq, md)));
+    let (outcome, seq) = match moved {
+        Ok((n, seq, md)) => {
+            let to = PathBuf::from(env::var("tc").unwrap()).join(&n);
+            info_log = finish_trash(target, &to, &n, &fty, md.permissions().mode(), last, now, user, timestamp_now)?;
+            (Outcome::Trashed(n), Some(seq))
+        }
+        Err(e) => {
+            info_log = format!(
+                "{} {} tried to delete {} \"{}\" while an error occured: {} \n",
+                now,
+                user,
+                fty,
+                escape_path(target),
+                e.message
+            );
+            (Outcome::Failed(e.message), None)
+        }
+    };
+    log.write_all(info_log.as_bytes())?;
+    // record and log line are written, a later run mustn't redo them
+    if let Some(seq) = seq {
+        journal.end(seq)?;
+    }
+    Ok(outcome)
+}
+
+/// Trash every target or none of them. Everything is validated up front;
+/// if a rename still fails, the items moved so far are put back, and those
+/// that can't be are recorded like any trashed item.
+fn move_to_trash_atomic(targets: Vec<PathBuf>, mut log: &File, now: &str, journal: &mut Journal) -> Result<(), AppError> {
+    interrupt::install();
+    let mut last = String::new();
+    let user: String = env::var("USER").unwrap_or("default".to_string());
+    let timestamp_now = now_timestamp();
+    let mut report = BatchReport::new();
+
+    // rename can't move anything to another file system
+    let trash_dev = Path::new(&env::var("tc").unwrap()).symlink_metadata()?.dev();
+    let mut problems: Vec<Option<String>> = Vec::new();
+    for target in &targets {
+        let problem = match target.symlink_metadata() {
+            Err(e) => Some(e.to_string()),
+            Ok(md) if md.dev() != trash_dev => Some("On another file system than the trash can".to_string()),
+            Ok(_) => cwd_problem(target).or_else(|| open_problem(target)),
+        };
+        problems.push(problem);
+    }
+    if problems.iter().any(|p| p.is_some()) {
+        for (target, problem) in targets.into_iter().zip(problems) {
+            match problem {
+                Some(reason) => {
+                    let info_log = format!(
+                        "{} {} tried to atomically delete \"{}\" while I refused: {}\n",
                         now,
                         &user,
-                        fty,
-                        target.display(),
+                        escape_path(&target),
+                        reason
                     );
+                    log.write_all(info_log.as_bytes())?;
+                    report.push(target, Outcome::Failed(reason));
                 }
+                None => report.push(target, Outcome::Skipped),
+            }
+        }
+        report.print_summary("trashed");
+        return report.into_result();
+    }
+
+    // (target, name in trash can, type, mode, journal sequence)
+    let mut moved: Vec<(PathBuf, String, String, u32, u64)> = Vec::new();
+    let mut failure: Option<(PathBuf, String)> = None;
+    let mut rest = targets.into_iter();
+    for target in rest.by_ref() {
+        if interrupt::interrupted() {
+            failure = Some((target, "interrupted".to_string()));
+            break;
+        }
+        let fty = get_type(&target);
+        let attempt = target
+            .symlink_metadata()
+            .map_err(AppError::from)
+            .and_then(|md| {
+                let mode = md.permissions().mode();
+                move_into_trash(&target, mode, timestamp_now, journal).map(|(n, seq)| (n, mode, seq))
+            });
+        match attempt {
+            Ok((n, mode, seq)) => moved.push((target, n, fty, mode, seq)),
+            Err(e) => {
+                failure = Some((target, e.message));
+                break;
+            }
+        }
+    }
+
+    if let Some((failed_target, reason)) = failure {
+        let info_log = format!(
+            "{} {} tried to atomically delete \"{}\" while an error occured: {}, rolling back\n",
+            now,
+            &user,
+            escape_path(&failed_target),
+            reason
+        );
+        log.write_all(info_log.as_bytes())?;
+        let mut rolled_back: Vec<(PathBuf, Outcome)> = Vec::new();
+        for (target, n, fty, mode, seq) in moved.into_iter().rev() {
+            let from = PathBuf::from(env::var("tc").unwrap()).join(&n);
+            let restore_seq = journal.begin(&Op::Restore {
+                name: n.clone(),
+                dst: target.clone(),
+                mode,
+            })?;
+            let (info_log, outcome) = match rename_noreplace(&from, &target) {
+                Ok(_) => (
+                    format!("{} {} rolled back \"{}\" <= {}\n", now, &user, escape_path(&target), &n),
+                    Outcome::RolledBack,
+                ),
                 Err(e) => {
-                    info_log = format!(
-                        "{} {} tried to permanently delete {} \"{}\" while an error occured: {} \n",
+                    // it stays in the trash, so it is recorded like any other item
+                    let deleted = finish_trash(&target, &from, &n, &fty, mode, &mut last, now, &user, timestamp_now)?;
+                    (
+                        format!(
+                            "{}{} {} tried to roll back \"{}\" <= {} while an error occured: {}\n",
+                            deleted,
+                            now,
+                            &user,
+                            escape_path(&target),
+                            &n,
+                            e
+                        ),
+                        Outcome::Failed(format!("rollback failed, still in trash as {}: {}", n, e)),
+                    )
+                }
+            };
+            log.write_all(info_log.as_bytes())?;
+            journal.end(restore_seq)?;
+            journal.end(seq)?;
+            rolled_back.push((target, outcome));
+        }
+        // what couldn't be put back can still be undone with -z
+        write_last(&last)?;
+        for (target, outcome) in rolled_back.into_iter().rev() {
+            report.push(target, outcome);
+        }
+        report.push(failed_target, Outcome::Failed(reason));
+        for target in rest {
+            report.push(target, Outcome::Skipped);
+        }
+        report.print_summary("trashed");
+        return report.into_result();
+    }
+
+    for (target, n, fty, mode, seq) in moved {
+        let to = PathBuf::from(env::var("tc").unwrap()).join(&n);
+        let finished = finish_trash(&target, &to, &n, &fty, mode, &mut last, now, &user, timestamp_now)
+            .and_then(|info_log| Ok(log.write_all(info_log.as_bytes())?));
+        if let Err(e) = finished {
+            // the items recorded so far stay undoable, the rest is left to the journal
+            write_last(&last)?;
+            return Err(e);
+        }
+        journal.end(seq)?;
+        report.push(target, Outcome::Trashed(n));
+    }
+    write_last(&last)?;
+    journal.commit()?;
+    report.print_summary("trashed");
+    report.into_result()
+}
+
+/// Settle the operations an earlier rmrs left half done when it was killed:
+/// moves and restores whose rename went through are completed, the others
+/// are dropped, and interrupted purges are finished.
+fn recover_journal(mut log: &File, now: &str) -> Result<(), AppError> {
+    let th = PathBuf::from(env::var("th").unwrap());
+    let tc = PathBuf::from(env::var("tc").unwrap());
+    let pending = Journal::pending(&th)?;
+    if pending.is_empty() {
+        // every run adds its finished operations, they are of no use now
+        Journal::clear(&th)?;
+        return Ok(());
+    }
+    let user: String = env::var("USER").unwrap_or("default".to_string());
+    let timestamp_now = now_timestamp();
+    let mut last = String::new();
+    // ids of the items that are back in place
+    let mut restored: Vec<String> = Vec::new();
+    for op in pending {
+        // 一个操作收拾不了也不能卡住以后的每一次运行
+        let mut settle = || -> Result<(), AppError> {
+            match op.clone() {
+                Op::Move { src, name, mode } => {
+                    let stored = tc.join(&name);
+                    if stored.symlink_metadata().is_err() || src.symlink_metadata().is_ok() {
+                        return Ok(());
+                    }
+                    let fty = get_type(&stored);
+                    finish_trash(&src, &stored, &name, &fty, mode, &mut last, now, &user, timestamp_now)?;
+                    let info_log = format!(
+                        "{} {} recovered interrupted deletion of {} \"{}\" ${:o}$ => {}\n",
                         now,
                         &user,
                         fty,
-                        target.display(),
-                        e
+                        escape_path(&src),
+                        mode % 512,
+                        &name
                     );
-                    eprintln!("{e}");
+                    log.write_all(info_log.as_bytes())?;
+                    eprintln!("recovered interrupted deletion of {}", quote_path(&src));
                 }
-            }
-            log.write_all(info_log.as_bytes())?;
-        } else {
-            match check_exist(target.file_name().unwrap().to_string_lossy().into_owned()) {
-                Ok(n) => {
-                    let fty = get_type(&target);
-                    // let dst = to.join(&n);
-                    let to = PathBuf::from(env::var("tc").unwrap()).join(&n);
-                    match rename(&target, &to) {
-                        Ok(_) => {
-                            let st_mode_perms = to.metadata()?.permissions().mode();
-                            let fp = to.to_str().unwrap();
-                            #[cfg(target_os="linux")]
-                            let mut mode: u32 = 0o000;
-                            #[cfg(target_os="macos")]
-                            let mut mode: u16 = 0o000;
-                            if to.is_dir() {
-                                mode = 0o600;
-                            }
-                            change_file_permissions(fp, mode).unwrap();
-                            update_file_mtime(fp, timestamp_now).unwrap();
-                            info_log = format!(
-                                "{} {} deleted {} \"{}\" ${:o}$ => {}\n",
-                                now,
-                                &user,
-                                fty,
-                                target.display(),
-                                st_mode_perms%512,
-                                &n
-                            );
-                            info_last = format!(
-                                "{} >> {} ${:o}$\n",
-                                PathBuf::from(env::var("tc").unwrap()).join(&n).display(),
-                                target.display(),
-                                st_mode_perms%512,
-                            );
-                            file_last.write_all(info_last.as_bytes())?;
-                        }
-                        Err(e) => {
-                            info_log = format!(
-                                "{} {} tried to delete {} \"{}\" while an error occured: {} \n",
-                                now,
-                                &user,
-                                fty,
-                                target.display(),
-                                e
-                            );
-                            eprintln!("{e}");
-                        }
+                Op::Restore { name, dst, mode } => {
+                    let stored = tc.join(&name);
+                    if stored.symlink_metadata().is_ok() || dst.symlink_metadata().is_err() {
+                        return Ok(());
                     }
+                    put_back_attrs(&dst, info::load(&th, &name).ok().and_then(|r| r.attrs).as_ref(), mode)?;
+                    info::remove(&th, &name)?;
+                    let info_log = format!(
+                        "{} {} recovered interrupted restore of \"{}\" ${:o}$ <= {}\n",
+                        now,
+                        &user,
+                        escape_path(&dst),
+                        mode % 512,
+                        &name
+                    );
                     log.write_all(info_log.as_bytes())?;
+                    restored.push(name);
+                    eprintln!("recovered interrupted restore of {}", quote_path(&dst));
                 }
-                Err(e) => {
-                    return Err(e);
+                Op::Stage { name, batch } => staging::settle_stage(&name, &batch)?,
+                Op::Unstage { name, batch } => staging::settle_unstage(&name, &batch)?,
+                Op::Purge { path } => {
+                    if path.symlink_metadata().is_err() {
+                        return Ok(());
+                    }
+                    if path.symlink_metadata()?.is_dir() {
+                        remove_dir_all(&path)?;
+                    } else {
+                        remove_file(&path)?;
+                    }
+                    let info_log = format!(
+                        "{} {} finished interrupted removal of \"{}\"\n",
+                        now,
+                        &user,
+                        escape_path(&path)
+                    );
+                    log.write_all(info_log.as_bytes())?;
+                    eprintln!("finished interrupted removal of {}", quote_path(&path));
                 }
             }
+            Ok(())
+        };
+        if let Err(e) = settle() {
+            let info_log = format!(
+                "{} {} tried to recover interrupted {} while an error occured: {}\n",
+                now,
+                &user,
+                interrupted_op(&op),
+                e.message
+            );
+            log.write_all(info_log.as_bytes())?;
+            eprintln!("could not recover interrupted {}: {}", interrupted_op(&op), e.message);
         }
     }
+    forget_in_last(&restored)?;
+    write_last(&last)?;
+    fs::create_dir_all(&tc)?;
+    Journal::clear(&th)?;
     Ok(())
 }
 
-fn regret(mut log: &File, now: &str) -> Result<(), AppError> {
-    let path_last = PathBuf::from(env::var("th").unwrap()).join(".last");
+/// What an operation in the journal was doing, for the log
+fn interrupted_op(op: &Op) -> String {
+    match op {
+        Op::Move { src, .. } => format!("deletion of \"{}\"", escape_path(src)),
+        Op::Restore { dst, .. } => format!("restore of \"{}\"", escape_path(dst)),
+        Op::Purge { path } => format!("removal of \"{}\"", escape_path(path)),
+        Op::Stage { name, .. } => format!("purge of \"{}\"", escape(name)),
+        Op::Unstage { name, .. } => format!("unclear of \"{}\"", escape(name)),
+    }
+}
+
+/// `rmrs restore`: put the given items, or those of the last batch, back
+fn restore_items(
+    items: Vec<OsString>,
+    options: &restore::Options,
+    log: &File,
+    now: &str,
+    journal: &mut Journal,
+) -> Result<(), AppError> {
+    interrupt::install();
+    let th = PathBuf::from(env::var("th").unwrap());
+    // each item with the part of it to restore, if only a part
+    let records: Vec<(TrashInfo, Option<PathBuf>)> = if items.is_empty() {
+        let path_last = th.join(".last");
+        if !path_last.exists() {
+            return Err(AppError {
+                code: -16,
+                message: "nothing to restore, the last batch is gone".to_string(),
+            });
+        }
+        fs::read_to_string(path_last)?
+            .lines()
+            .map(|l| match parse_last_line(OsStr::new(l)) {
+                Some(record) => Ok((restore::from_last(record), None)),
+                None => info::load(&th, l).map(|record| (record, None)),
+            })
+            .collect::<Result<_, _>>()?
+    } else {
+        let mut records = Vec::new();
+        for item in &items {
+            // `find` prints things inside items as `<id>/<path>`
+            let (id, subpath) = browse::parse_spec(item);
+            if subpath.is_some() && options.path.is_some() {
+                return Err(AppError {
+                    code: -16,
+                    message: format!("{} already names a path inside the item, leave out --path", quote(item)),
+                });
+            }
+            records.push((info::resolve(&th, &id)?, subpath));
+        }
+        records
+    };
+    let mut report = BatchReport::new();
+    let mut restored: Vec<String> = Vec::new();
+    for (record, subpath) in records {
+        let options = &restore::Options {
+            path: subpath.or(options.path.clone()),
+            ..options.clone()
+        };
+        let dst = restore::destination(&record, options);
+        if interrupt::interrupted() {
+            report.push(dst, Outcome::Skipped);
+            continue;
+        }
+        let outcome = restore::restore_one(&record, options, log, now, journal)?;
+        // what is left of a partly restored item stays undoable
+        if matches!(outcome, Outcome::Restored) && options.path.is_none() {
+            restored.push(record.id);
+        }
+        report.push(dst, outcome);
+    }
+    forget_in_last(&restored)?;
+    journal.commit()?;
+    report.print_summary("restored");
+    if interrupt::interrupted() {
+        return Err(interrupted_error());
+    }
+    report.into_result()
+}
+
+/// `rmrs restore --under/--since/--until`: show everything that matches,
+/// and after confirmation restore it as one operation
+fn restore_selected(
+    sub: &clap::ArgMatches,
+    options: &restore::Options,
+    mut log: &File,
+    now: &str,
+    journal: &mut Journal,
+) -> Result<(), AppError> {
+    let under = sub.get_one::<OsString>("under").map(|p| conv_to_abs(vec![p.as_os_str()]).remove(0));
+    let since = sub.get_one::<String>("since").map(|t| info::parse_time(t)).transpose()?;
+    let until = sub.get_one::<String>("until").map(|t| info::parse_time_end(t)).transpose()?;
+    let selected = restore::select(under.as_deref(), since, until)?;
+    if selected.is_empty() {
+        return Err(AppError {
+            code: -16,
+            message: "nothing in the trash matches".to_string(),
+        });
+    }
+    for record in &selected {
+        println!(
+            "{} {} {}",
+            quote(OsStr::new(&record.id)),
+            record.deleted_time(),
+            quote_path(&record.path)
+        );
+    }
+    println!("{} items will be restored", selected.len());
+    if !confirm() {
+        return Ok(());
+    }
+    let mut filter = String::new();
+    if let Some(under) = &under {
+        filter.push_str(&format!(" under \"{}\"", escape_path(under)));
+    }
+    if let Some(since) = sub.get_one::<String>("since") {
+        filter.push_str(&format!(" since {}", since));
+    }
+    if let Some(until) = sub.get_one::<String>("until") {
+        filter.push_str(&format!(" until {}", until));
+    }
+    let user: String = env::var("USER").unwrap_or("default".to_string());
+    let info_log = format!("{} {} restoring {} items deleted{}\n", now, &user, selected.len(), filter);
+    log.write_all(info_log.as_bytes())?;
+    let ids: Vec<OsString> = selected.into_iter().map(|r| OsString::from(r.id)).collect();
+    let result = restore_items(ids, options, log, now, journal);
+    let info_log = format!(
+        "{} {} finished restoring items deleted{}{}\n",
+        now,
+        &user,
+        filter,
+        if result.is_ok() { "" } else { ", some failed" }
+    );
+    log.write_all(info_log.as_bytes())?;
+    result
+}
+
+fn regret(mut log: &File, now: &str, journal: &mut Journal) -> Result<(), AppError> {
+    let th = PathBuf::from(env::var("th").unwrap());
+    let path_last = th.join(".last");
     let f = File::open(&path_last)?;
     let mut reader = BufReader::new(f);
     let mut lines: Vec<String> = vec![];
     let mut line = String::new();
     line.clear();
     let mut len = reader.read_line(&mut line)?;
-    let re_paths = Regex::new(r"/{1,1}[/+.?_?,?\-?\w?]+")?;
-    let re_perms = Regex::new(r"\$([0-9]{3})\$$")?;
     while len > 0 {
         line.pop();
         lines.push(line.clone());
         line.clear();
         len = reader.read_line(&mut line)?;
     }
-    for li in lines {
-        let mut v: Vec<String> = Vec::new();
-        for cap in re_paths.captures_iter(&li) {
-            v.push(cap[0].to_string());
+    interrupt::install();
+    for (i, li) in lines.iter().enumerate() {
+        if interrupt::interrupted() {
+            // 只保留尚未恢复的记录
+            write_last(&lines[i..].iter().map(|l| format!("{}\n", l)).collect::<String>())?;
+            return Err(interrupted_error());
         }
-        let perms_cap = re_perms.captures(&li).unwrap();
+        // 旧版本的.last记录的是 `<stored> >> <original> $<mode>$`
+        let (id, stored, dst, mode, saved_attrs) = match parse_last_line(OsStr::new(li)) {
+            Some(record) => (
+                record.stored.file_name().unwrap().to_string_lossy().into_owned(),
+                record.stored,
+                record.original,
+                record.mode,
+                None,
+            ),
+            None => match info::load(&th, li) {
+                Ok(record) => (record.id.clone(), record.stored(), record.path, record.mode, record.attrs),
+                Err(e) if e.code == -1 => return undo_gone(log, now, li, &lines[i..]),
+                Err(e) => return Err(e),
+            },
+        };
         #[allow(unused_assignments)]
         let mut log_info: String = String::new();
-        match fs::rename(&v[0], &v[1]) {
+        let seq = journal.begin(&Op::Restore {
+            name: id.clone(),
+            dst: dst.clone(),
+            mode,
+        })?;
+        // 不覆盖原位置上已有的文件
+        let renamed = rename_noreplace(&stored, &dst);
+        if renamed.is_ok() {
+            put_back_attrs(&dst, saved_attrs.as_ref(), mode)?;
+            info::remove(&th, &id)?;
+        }
+        journal.end(seq)?;
+        match renamed {
             Ok(_) => {
-                #[cfg(target_os="macos")]
-                change_file_permissions(&v[1], u16::from_str_radix(&perms_cap[1], 8).unwrap()).unwrap();
                 log_info = format!(
                     "{} {} undid last opeation successfully\n",
                     now,
@@ -294,27 +1187,35 @@ fn regret(mut log: &File, now: &str) -> Result<(), AppError> {
                 log.write_all(log_info.as_bytes())?;
             }
             Err(e) => {
-                match e.kind() {
-                    std::io::ErrorKind::NotFound => {
-                        log_info = format!(
-                            "{} {} tried to undo last operation while an error occured: {}\n",
-                            now,
-                            env::var("USER").unwrap_or("default".to_string()),
-                            e.kind().to_string()
-                        );
-                        log.write_all(log_info.as_bytes())?;
-                        // 发生NotFound错误，删除.last文件
-                        remove_file(&path_last)?;
-                    }
-                    _ => {
-                        log_info = format!(
-                            "{} {} tried to undo last operation while an error occured: {}\n",
-                            now,
-                            env::var("USER").unwrap_or("default".to_string()),
-                            e.kind().to_string()
-                        );
-                        log.write_all(log_info.as_bytes())?;
-                    }
+                log_info = format!(
+                    "{} {} tried to undo last operation while an error occured: {}\n",
+                    now,
+                    env::var("USER").unwrap_or("default".to_string()),
+                    e.kind()
+                );
+                log.write_all(log_info.as_bytes())?;
+                // 已恢复的记录不再保留，其余的留给下一次
+                write_last(&lines[i..].iter().map(|l| format!("{}\n", l)).collect::<String>())?;
+                if e.kind() == std::io::ErrorKind::AlreadyExists {
+                    return Err(AppError {
+                        code: -19,
+                        message: format!(
+                            "{} already exists, restore with `rmrs restore --on-conflict=...`",
+                            quote_path(&dst)
+                        ),
+                    });
+                }
+                if let Some(parent) = dst.parent().filter(|p| p.symlink_metadata().is_err()) {
+                    return Err(AppError {
+                        code: -1,
+                        message: format!(
+                            "{} is gone, restore with `rmrs restore --parents` or `--to`",
+                            quote_path(parent)
+                        ),
+                    });
+                }
+                if e.kind() == std::io::ErrorKind::NotFound {
+                    return undo_gone(log, now, &id, &lines[i..]);
                 }
                 return Err(e.into());
             }
@@ -325,29 +1226,86 @@ fn regret(mut log: &File, now: &str) -> Result<(), AppError> {
     Ok(())
 }
 
-fn clear(mut log: &File, now: &str) -> Result<(), AppError> {
+/// `-z` found `id` missing from the trash can: either `clear` or `versions
+/// --keep` purged it and `rmrs unclear` can still bring it back, or it is
+/// gone for good
+fn undo_gone(mut log: &File, now: &str, id: &str, pending: &[String]) -> Result<(), AppError> {
+    let user: String = env::var("USER").unwrap_or("default".to_string());
+    let batch = staging::holding(id)?;
+    let reason = match &batch {
+        Some(batch) => format!("{} was purged as batch {}", id, batch.id),
+        None => format!("{} is no longer in the trash", id),
+    };
+    let log_info = format!("{} {} tried to undo last operation while an error occured: {}\n", now, &user, reason);
+    log.write_all(log_info.as_bytes())?;
+    match batch {
+        Some(batch) => {
+            // 留着.last，unclear之后还能再撤销
+            write_last(&pending.iter().map(|l| format!("{}\n", l)).collect::<String>())?;
+            Err(AppError {
+                code: -1,
+                message: format!(
+                    "{} was purged, run `rmrs unclear {}` and undo again",
+                    quote(OsStr::new(id)),
+                    batch.id
+                ),
+            })
+        }
+        None => {
+            // 和以前一样，找不到就删除.last文件
+            remove_file(PathBuf::from(env::var("th").unwrap()).join(".last"))?;
+            Err(AppError {
+                code: -1,
+                message: format!("{} is no longer in the trash, nothing to undo", quote(OsStr::new(id))),
+            })
+        }
+    }
+}
+
+fn clear(mut log: &File, now: &str, for_good: bool, journal: &mut Journal) -> Result<(), AppError> {
     if confirm() {
-        #[allow(unused_assignments)]
-        let mut log_info: String = String::new();
-        match remove_dir_all(PathBuf::from(env::var("th").unwrap()).join("files")) {
-            Ok(_) => {
-                log_info = format!(
-                    "{} {} cleaned trash can\n",
-                    now,
-                    env::var("USER").unwrap_or("default".to_string())
-                );
+        let th = PathBuf::from(env::var("th").unwrap());
+        let user: String = env::var("USER").unwrap_or("default".to_string());
+        let mut names: Vec<OsString> = info::ids(&th)?.into_iter().map(OsString::from).collect();
+        for entry in fs::read_dir(env::var("tc").unwrap())? {
+            names.push(entry?.file_name());
+        }
+        names.sort();
+        names.dedup();
+        if names.is_empty() {
+            println!("the trash is empty");
+            return Ok(());
+        }
+        // 先挪到purged里，过了宽限期再真正删除
+        let batch = staging::create()?;
+        let mut staged: Result<(), AppError> = Ok(());
+        for name in &names {
+            staged = staging::stage(&batch, name, journal);
+            if staged.is_err() {
+                break;
             }
+        }
+        journal.commit()?;
+        let log_info = match &staged {
+            Ok(_) => format!("{} {} cleaned trash can: {} items purged as batch {}\n", now, &user, names.len(), batch.id),
             Err(e) => {
-                log_info = format!(
-                    "{} {} tried to clean trash can while an error occured: {}",
-                    now,
-                    env::var("USER").unwrap_or("default".to_string()),
-                    e
-                );
-                eprintln!("{e}");
+                eprintln!("{}", e.message);
+                format!(
+                    "{} {} tried to clean trash can while an error occured: {}\n",
+                    now, &user, e.message
+                )
             }
-        }
+        };
         log.write_all(log_info.as_bytes())?;
+        if for_good || staging::grace() == 0 {
+            staging::delete(&batch, log, now, journal)?;
+        } else if staged.is_ok() {
+            println!(
+                "{} items purged, `rmrs unclear` brings them back until {}",
+                names.len(),
+                info::local_time(batch.expires_at())
+            );
+        }
     }
     Ok(())
 }
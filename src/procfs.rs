@@ -0,0 +1,100 @@
+use crate::display::quote;
+use std::{
+    ffi::{OsStr, OsString},
+    fmt::Display,
+    fs,
+    os::unix::ffi::OsStrExt,
+    path::{Path, PathBuf},
+    sync::OnceLock,
+};
+
+/// A running process as seen in `/proc`
+#[derive(Debug, Clone)]
+pub struct Process {
+    pub pid: u32,
+    /// command name from `/proc/<pid>/comm`, which the process sets itself
+    pub comm: OsString,
+}
+
+impl Display for Process {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "pid {} ({})", self.pid, quote(&self.comm))
+    }
+}
+
+/// Every process except this one. Processes of other users are listed too,
+/// but most of their links can only be read by root.
+fn others() -> Vec<Process> {
+    let own = std::process::id();
+    let Ok(entries) = fs::read_dir("/proc") else {
+        return Vec::new();
+    };
+    entries
+        .filter_map(|entry| entry.ok()?.file_name().to_str()?.parse::<u32>().ok())
+        .filter(|pid| *pid != own)
+        .map(|pid| Process {
+            pid,
+            comm: fs::read(format!("/proc/{}/comm", pid))
+                .map(|c| OsStr::from_bytes(c.strip_suffix(b"\n").unwrap_or(&c)).to_os_string())
+                .unwrap_or_default(),
+        })
+        .collect()
+}
+
+/// Processes whose working directory is `dir` or somewhere beneath it.
+/// `dir` has to be canonical, like the links in `/proc`.
+pub fn cwd_users(dir: &Path) -> Vec<Process> {
+    others()
+        .into_iter()
+        .filter(|p| {
+            fs::read_link(format!("/proc/{}/cwd", p.pid))
+                .map(|cwd| cwd.starts_with(dir))
+                .unwrap_or(false)
+        })
+        .collect()
+}
+
+/// Files held open by other processes, read once per run: a batch of
+/// thousands of targets would otherwise walk every descriptor again and again
+fn open_files() -> &'static [(Process, PathBuf)] {
+    static OPEN_FILES: OnceLock<Vec<(Process, PathBuf)>> = OnceLock::new();
+    OPEN_FILES.get_or_init(|| {
+        let mut found = Vec::new();
+        for p in others() {
+            let Ok(fds) = fs::read_dir(format!("/proc/{}/fd", p.pid)) else {
+                continue;
+            };
+            for fd in fds.flatten() {
+                // pipes, sockets and the like aren't paths and never match
+                if let Ok(path) = fs::read_link(fd.path()) {
+                    found.push((p.clone(), path));
+                }
+            }
+        }
+        found
+    })
+}
+
+/// Processes holding `path`, or anything beneath it, open. `path` has to be
+/// canonical, like the links in `/proc`.
+pub fn holders(path: &Path) -> Vec<Process> {
+    let mut holders: Vec<Process> = Vec::new();
+    for (p, open) in open_files() {
+        if open.starts_with(path) && !holders.iter().any(|h| h.pid == p.pid) {
+            holders.push(p.clone());
+        }
+    }
+    holders
+}
+
+/// `path` with its parent directories resolved, the last component is kept
+/// as is so that a symlink stays the symlink itself
+pub fn canonical(path: &Path) -> PathBuf {
+    match (path.parent(), path.file_name()) {
+        (Some(parent), Some(name)) => match parent.canonicalize() {
+            Ok(parent) => parent.join(name),
+            Err(_) => path.to_path_buf(),
+        },
+        _ => path.to_path_buf(),
+    }
+}
@@ -0,0 +1,99 @@
+use crate::display::quote_path;
+use crate::error::AppError;
+use std::path::PathBuf;
+
+/// What happened to a single target of a batch
+#[derive(Debug)]
+pub enum Outcome {
+    /// moved into the trash under the given name
+    Trashed(String),
+    /// removed from disc
+    Deleted,
+    /// moved out of the trash again
+    Restored,
+    Failed(String),
+    /// moved into the trash, then put back because the atomic batch failed
+    RolledBack,
+    /// not attempted because another target failed first
+    Skipped,
+}
+
+/// Collects the per-target outcomes of one invocation
+#[derive(Debug, Default)]
+pub struct BatchReport {
+    pub entries: Vec<(PathBuf, Outcome)>,
+}
+
+impl BatchReport {
+    pub fn new() -> BatchReport {
+        Self {
+            entries: Vec::new(),
+        }
+    }
+
+    pub fn push(&mut self, target: PathBuf, outcome: Outcome) {
+        self.entries.push((target, outcome));
+    }
+
+    pub fn failed(&self) -> usize {
+        self.entries
+            .iter()
+            .filter(|(_, o)| matches!(o, Outcome::Failed(_)))
+            .count()
+    }
+
+    pub fn skipped(&self) -> usize {
+        self.entries
+            .iter()
+            .filter(|(_, o)| matches!(o, Outcome::Skipped))
+            .count()
+    }
+
+    pub fn rolled_back(&self) -> usize {
+        self.entries
+            .iter()
+            .filter(|(_, o)| matches!(o, Outcome::RolledBack))
+            .count()
+    }
+
+    pub fn succeeded(&self) -> usize {
+        self.entries.len() - self.failed() - self.skipped() - self.rolled_back()
+    }
+
+    /// Print the failures with their reasons and a one-line summary.
+    /// A lone successful target stays silent like `rm` does.
+    pub fn print_summary(&self, verb: &str) {
+        if self.entries.len() == 1 && self.failed() == 0 {
+            return;
+        }
+        for (target, outcome) in &self.entries {
+            if let Outcome::Failed(reason) = outcome {
+                eprintln!("failed: {}: {}", quote_path(target), reason);
+            }
+        }
+        let mut summary = format!("{} {}, {} failed", self.succeeded(), verb, self.failed());
+        if self.rolled_back() > 0 {
+            summary = format!("{}, {} rolled back", summary, self.rolled_back());
+        }
+        if self.skipped() > 0 {
+            summary = format!("{}, {} skipped", summary, self.skipped());
+        }
+        if self.failed() > 0 {
+            eprintln!("{}", summary);
+        } else {
+            println!("{}", summary);
+        }
+    }
+
+    /// Turn the report into the exit status of the process
+    pub fn into_result(self) -> Result<(), AppError> {
+        let failed = self.failed();
+        if failed > 0 {
+            return Err(AppError {
+                code: -11,
+                message: format!("{} of {} targets failed", failed, self.entries.len()),
+            });
+        }
+        Ok(())
+    }
+}
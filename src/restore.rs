@@ -0,0 +1,584 @@
+use crate::attrs::{self, Attrs};
+use crate::display::{escape_path, quote_path};
+use crate::error::AppError;
+use crate::history::LastRecord;
+use crate::info::{self, TrashInfo};
+use crate::journal::{Journal, Op};
+use crate::report::Outcome;
+use crate::{change_file_permissions, finish_trash, get_type, move_into_trash, now_timestamp, rename_noreplace};
+use std::{
+    env,
+    fs::{self, File},
+    io::{self, stdin, stdout, IsTerminal, Write},
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+};
+
+/// What to do when something already exists where an item is restored to
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum Conflict {
+    /// leave both where they are
+    #[default]
+    Skip,
+    /// restore next to the existing item under a free name
+    Rename,
+    /// move the existing item into the trash to make room
+    Overwrite,
+    /// move the contents of a directory into the existing directory
+    Merge,
+    /// ask for every conflict
+    Ask,
+}
+
+impl Conflict {
+    pub const NAMES: [&'static str; 5] = ["skip", "rename", "overwrite", "merge", "ask"];
+
+    pub fn parse(name: &str) -> Option<Conflict> {
+        match name {
+            "skip" => Some(Conflict::Skip),
+            "rename" => Some(Conflict::Rename),
+            "overwrite" => Some(Conflict::Overwrite),
+            "merge" => Some(Conflict::Merge),
+            "ask" => Some(Conflict::Ask),
+            _ => None,
+        }
+    }
+}
+
+/// Where and how `rmrs restore` puts items back
+#[derive(Debug, Default, Clone)]
+pub struct Options {
+    /// directory to restore into instead of the original location
+    pub to: Option<PathBuf>,
+    /// recreate missing parent directories
+    pub parents: bool,
+    pub on_conflict: Conflict,
+    /// restore only this file or directory inside the item
+    pub path: Option<PathBuf>,
+}
+
+/// Give a restored item back the mode and attributes it had before deletion.
+/// Records of older versions only know the mode.
+pub fn put_back_attrs(dst: &Path, saved: Option<&Attrs>, mode: u32) -> Result<(), AppError> {
+    let warnings = match saved {
+        Some(saved) => attrs::apply(dst, saved, mode)?,
+        None => {
+            let md = dst.symlink_metadata()?;
+            // chmod would follow a symlink to its target
+            if md.file_type().is_symlink() || md.permissions().mode() & 0o7777 == mode & 0o7777 {
+                Vec::new()
+            } else {
+                match change_file_permissions(dst, (mode & 0o7777) as _) {
+                    Ok(_) => Vec::new(),
+                    Err(e) => vec![format!("could not restore mode {:03o}: {}", mode & 0o7777, e)],
+                }
+            }
+        }
+    };
+    for warning in warnings {
+        eprintln!("{}: {}", quote_path(dst), warning);
+    }
+    Ok(())
+}
+
+/// Stand-in record for an item trashed by an older version, which only
+/// left a line in `.last`
+pub fn from_last(record: LastRecord) -> TrashInfo {
+    TrashInfo {
+        id: record.stored.file_name().unwrap_or_default().to_string_lossy().into_owned(),
+        name: record.original.file_name().unwrap_or_default().to_os_string(),
+        kind: get_type(&record.stored),
+        path: record.original,
+        mode: record.mode,
+        deleted_at: 0,
+        user: "unknown".to_string(),
+        attrs: None,
+        parent_mode: None,
+    }
+}
+
+/// The newest item deleted from each path under `under` within the time
+/// range, parents before what was inside them
+pub fn select(under: Option<&Path>, since: Option<i64>, until: Option<i64>) -> Result<Vec<TrashInfo>, AppError> {
+    let th = PathBuf::from(env::var("th").unwrap());
+    let mut selected: Vec<TrashInfo> = Vec::new();
+    // newest first, so that only the newest version of a path is kept
+    for record in info::all(&th)?.into_iter().rev() {
+        let matched = under.is_none_or(|u| record.path.starts_with(u))
+            && since.is_none_or(|t| record.deleted_at >= t)
+            && until.is_none_or(|t| record.deleted_at <= t);
+        if matched && !selected.iter().any(|r| r.path == record.path) {
+            selected.push(record);
+        }
+    }
+    selected.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(selected)
+}
+
+/// Where the item of `record`, or the part of it picked with `--path`, goes back to
+pub fn destination(record: &TrashInfo, options: &Options) -> PathBuf {
+    match (&options.to, &options.path) {
+        (Some(dir), Some(sub)) => dir.join(sub.file_name().unwrap_or(sub.as_os_str())),
+        (Some(dir), None) => dir.join(&record.name),
+        (None, Some(sub)) => record.path.join(sub),
+        (None, None) => record.path.clone(),
+    }
+}
+
+/// What is taken out of the trash can: the item of `record` or, with
+/// `--path`, something inside of it
+fn source(record: &TrashInfo, options: &Options) -> Result<PathBuf, String> {
+    match &options.path {
+        Some(sub) => record.locate(sub),
+        None => Ok(record.stored()),
+    }
+}
+
+/// Move the item of `record`, or the part of it picked with `--path`, out
+/// of the trash can. The record is dropped once the whole item is out and
+/// stays if anything goes wrong; `Err` is reserved for failures that should
+/// stop the whole batch, e.g. the log being unwritable.
+pub fn restore_one(
+    record: &TrashInfo,
+    options: &Options,
+    mut log: &File,
+    now: &str,
+    journal: &mut Journal,
+) -> Result<Outcome, AppError> {
+    let th = PathBuf::from(env::var("th").unwrap());
+    let user: String = env::var("USER").unwrap_or("default".to_string());
+    let whole = options.path.is_none();
+    // how the log refers to what is restored
+    let from = match &options.path {
+        Some(sub) => format!("{}/{}", record.id, escape_path(sub)),
+        None => record.id.clone(),
+    };
+    let mut dst = destination(record, options);
+    let failed = |mut log: &File, dst: &Path, reason: String| -> Result<Outcome, AppError> {
+        let info_log = format!(
+            "{} {} tried to restore {} \"{}\" <= {} while an error occured: {}\n",
+            now,
+            &user,
+            record.kind,
+            escape_path(dst),
+            from,
+            reason
+        );
+        log.write_all(info_log.as_bytes())?;
+        Ok(Outcome::Failed(reason))
+    };
+    let src = match source(record, options) {
+        Ok(src) => src,
+        Err(reason) => return failed(log, &dst, reason),
+    };
+    let (kind, mode) = if whole {
+        (record.kind.clone(), record.mode)
+    } else {
+        (get_type(&src), src.symlink_metadata()?.permissions().mode())
+    };
+    if let Ok(existing) = dst.symlink_metadata() {
+        let policy = match options.on_conflict {
+            Conflict::Ask => ask_conflict(&dst),
+            policy => policy,
+        };
+        let decision = match policy {
+            Conflict::Rename => {
+                let free = free_name(&dst);
+                let decision = format!("restored {} as \"{}\"", from, escape_path(&free));
+                dst = free;
+                decision
+            }
+            Conflict::Overwrite => {
+                // the existing item is trashed like any other, but it isn't
+                // part of a batch that `-z` could undo
+                let mode = existing.permissions().mode();
+                let deleted_at = now_timestamp();
+                let (id, seq) = move_into_trash(&dst, mode, deleted_at, journal)?;
+                let to = record.stored().with_file_name(&id);
+                let fty = get_type(&to);
+                let info_log = finish_trash(&dst, &to, &id, &fty, mode, &mut String::new(), now, &user, deleted_at)?;
+                log.write_all(info_log.as_bytes())?;
+                journal.end(seq)?;
+                format!("moved the existing item into the trash as {}", id)
+            }
+            Conflict::Merge => {
+                if !(existing.is_dir() && src.symlink_metadata()?.is_dir()) {
+                    return failed(log, &dst, format!("can't merge {} into {}", kind, get_type(&dst)));
+                }
+                let mut decisions: Vec<String> = Vec::new();
+                let seq = journal.begin(&Op::Restore {
+                    name: record.id.clone(),
+                    dst: dst.clone(),
+                    mode,
+                })?;
+                let merged = merge_dir(&src, &dst, &mut decisions);
+                if merged.is_ok() && whole {
+                    info::remove(&th, &record.id)?;
+                }
+                journal.end(seq)?;
+                for decision in decisions {
+                    log_conflict(log, now, &user, &dst, &decision)?;
+                }
+                if let Err(e) = merged {
+                    return failed(log, &dst, format!("merge stopped halfway: {}", e));
+                }
+                log_conflict(log, now, &user, &dst, &format!("merged {} into it", from))?;
+                return Ok(Outcome::Restored);
+            }
+            _ => {
+                log_conflict(log, now, &user, &dst, &format!("kept the existing item, {} stays in the trash", from))?;
+                eprintln!("skipped {}: already exists", quote_path(&dst));
+                return Ok(Outcome::Skipped);
+            }
+        };
+        log_conflict(log, now, &user, &destination(record, options), &decision)?;
+    }
+    let created = match make_parents(&th, &dst, record, options) {
+        Ok(created) => created,
+        Err(reason) => return failed(log, &dst, reason),
+    };
+    let seq = journal.begin(&Op::Restore {
+        name: record.id.clone(),
+        dst: dst.clone(),
+        mode,
+    })?;
+    let moved = rename_noreplace(&src, &dst);
+    // what lies inside an item kept its attributes in the trash can
+    if moved.is_ok() && whole {
+        put_back_attrs(&dst, record.attrs.as_ref(), mode)?;
+        info::remove(&th, &record.id)?;
+    }
+    journal.end(seq)?;
+    if let Err(e) = moved {
+        for (dir, _) in created.iter().rev() {
+            let _ = fs::remove_dir(dir);
+        }
+        return failed(log, &dst, e.to_string());
+    }
+    // a read-only directory would have blocked the rename, so the modes of
+    // recreated directories are set last, deepest first
+    for (dir, mode) in created.iter().rev() {
+        if let Some(mode) = mode {
+            if let Err(e) = change_file_permissions(dir, (mode & 0o7777) as _) {
+                eprintln!("{}: could not restore mode {:03o}: {}", quote_path(dir), mode & 0o7777, e);
+            }
+        }
+        let info_log = format!(
+            "{} {} recreated directory \"{}\" ${:o}$\n",
+            now,
+            &user,
+            escape_path(dir),
+            dir.symlink_metadata()?.permissions().mode() % 512
+        );
+        log.write_all(info_log.as_bytes())?;
+    }
+    let info_log = format!(
+        "{} {} restored {} \"{}\" ${:o}$ <= {}\n",
+        now,
+        &user,
+        kind,
+        escape_path(&dst),
+        mode % 512,
+        from
+    );
+    log.write_all(info_log.as_bytes())?;
+    Ok(Outcome::Restored)
+}
+
+/// Create the missing ancestors of `dst`, those above the item itself only
+/// with `--parents`, and return them, outermost first, with the mode each
+/// should get. A directory gets the mode it had when `record` was deleted
+/// from it, the mode of its copy inside the item, or the mode of its own
+/// record if it is in the trash too.
+fn make_parents(
+    th: &Path,
+    dst: &Path,
+    record: &TrashInfo,
+    options: &Options,
+) -> Result<Vec<(PathBuf, Option<u32>)>, String> {
+    let Some(parent) = dst.parent() else {
+        return Ok(Vec::new());
+    };
+    if parent.symlink_metadata().is_ok() {
+        return Ok(Vec::new());
+    }
+    let mut missing: Vec<&Path> = parent
+        .ancestors()
+        .take_while(|dir| dir.symlink_metadata().is_err())
+        .collect();
+    missing.reverse();
+    // with --path the directories of the item itself are in the trash too,
+    // so recreating them is part of the restore
+    let is_inside = |dir: &Path| options.to.is_none() && options.path.is_some() && dir.starts_with(&record.path);
+    if let Some(outside) = missing.iter().find(|dir| !is_inside(dir)) {
+        if !options.parents {
+            let hint = match options.to {
+                Some(_) => "restore with --parents",
+                None => "restore with --parents or --to",
+            };
+            return Err(format!("{} is gone, {}", quote_path(outside), hint));
+        }
+    }
+    let trashed_dirs: Vec<TrashInfo> = info::all(th)
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|r| r.kind == "directory")
+        .collect();
+    let mut created: Vec<(PathBuf, Option<u32>)> = Vec::new();
+    for dir in missing {
+        if let Err(e) = fs::create_dir(dir) {
+            for (dir, _) in created.iter().rev() {
+                let _ = fs::remove_dir(dir);
+            }
+            return Err(format!("could not create {}: {}", quote_path(dir), e));
+        }
+        let own = if options.to.is_none() && Some(dir) == record.path.parent() {
+            record.parent_mode
+        } else {
+            None
+        };
+        // a directory inside the item itself, left behind by a partial restore
+        let inside = match dir.strip_prefix(&record.path) {
+            Ok(rel) if options.to.is_none() => record.stored().join(rel).symlink_metadata().ok().map(|md| md.permissions().mode()),
+            _ => None,
+        };
+        let mode = own
+            .or(inside)
+            .or_else(|| trashed_dirs.iter().rev().find(|r| r.path == dir).map(|r| r.mode));
+        created.push((dir.to_path_buf(), mode));
+    }
+    Ok(created)
+}
+
+fn log_conflict(mut log: &File, now: &str, user: &str, dst: &Path, decision: &str) -> Result<(), AppError> {
+    let info_log = format!(
+        "{} {} found \"{}\" occupied on restore and {}\n",
+        now,
+        user,
+        escape_path(dst),
+        decision
+    );
+    log.write_all(info_log.as_bytes())?;
+    Ok(())
+}
+
+/// Ask what to do about `dst` being occupied; without a terminal nothing is touched
+fn ask_conflict(dst: &Path) -> Conflict {
+    if !stdin().is_terminal() {
+        return Conflict::Skip;
+    }
+    print!(
+        "{} already exists, [s]kip, [r]ename, [o]verwrite or [m]erge? ",
+        quote_path(dst)
+    );
+    stdout().flush().unwrap();
+    let mut answer = String::new();
+    stdin().read_line(&mut answer).unwrap();
+    match answer.trim() {
+        "r" => Conflict::Rename,
+        "o" => Conflict::Overwrite,
+        "m" => Conflict::Merge,
+        _ => Conflict::Skip,
+    }
+}
+
+/// The first of `<name>.restored`, `<name>.restored.2`, ... that doesn't exist yet
+fn free_name(dst: &Path) -> PathBuf {
+    let mut name = dst.file_name().unwrap_or_default().to_os_string();
+    name.push(".restored");
+    let mut free = dst.with_file_name(&name);
+    let mut n = 2;
+    while free.symlink_metadata().is_ok() {
+        let mut numbered = name.clone();
+        numbered.push(format!(".{}", n));
+        free = dst.with_file_name(numbered);
+        n += 1;
+    }
+    free
+}
+
+/// Move everything in the trashed directory `from` into the existing
+/// directory `into`, descending into directories both have. A file that is
+/// in the way is kept and the trashed one is put next to it under a free
+/// name; every such decision is added to `decisions`.
+fn merge_dir(from: &Path, into: &Path, decisions: &mut Vec<String>) -> io::Result<()> {
+    for entry in fs::read_dir(from)? {
+        let src = entry?.path();
+        let dst = into.join(src.file_name().unwrap());
+        match dst.symlink_metadata() {
+            Err(_) => rename_noreplace(&src, &dst)?,
+            Ok(md) if md.is_dir() && src.symlink_metadata()?.is_dir() => merge_dir(&src, &dst, decisions)?,
+            Ok(_) => {
+                let free = free_name(&dst);
+                rename_noreplace(&src, &free)?;
+                decisions.push(format!(
+                    "kept \"{}\", restored the trashed one as \"{}\"",
+                    escape_path(&dst),
+                    escape_path(&free)
+                ));
+            }
+        }
+    }
+    fs::remove_dir(from)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::OnceLock;
+
+    /// A trash home for this test run; `th` and `tc` are process wide, so
+    /// every test gets the same one
+    fn trash_home() -> &'static Path {
+        static HOME: OnceLock<PathBuf> = OnceLock::new();
+        HOME.get_or_init(|| {
+            let th = env::temp_dir().join(format!("rmrs-restore-test-{}", std::process::id()));
+            fs::create_dir_all(th.join("files")).unwrap();
+            env::set_var("th", &th);
+            env::set_var("tc", th.join("files"));
+            th
+        })
+    }
+
+    /// An empty directory of its own for `test`
+    fn scratch(test: &str) -> PathBuf {
+        let dir = trash_home().with_extension(test);
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn record(id: &str, path: &Path) -> TrashInfo {
+        TrashInfo {
+            id: id.to_string(),
+            name: path.file_name().unwrap().to_os_string(),
+            path: path.to_path_buf(),
+            kind: "file".to_string(),
+            mode: 0o100644,
+            deleted_at: 0,
+            user: "tester".to_string(),
+            attrs: None,
+            parent_mode: None,
+        }
+    }
+
+    #[test]
+    fn parents_are_only_made_with_parents() {
+        let dir = scratch("parents");
+        let mut item = record("20240101T000000-00000001", &dir.join("a/b/f.txt"));
+        item.parent_mode = Some(0o40700);
+        let options = Options::default();
+        let err = make_parents(trash_home(), &item.path, &item, &options).unwrap_err();
+        assert!(err.contains("--parents or --to"), "{}", err);
+        assert!(!dir.join("a").exists());
+
+        let options = Options {
+            parents: true,
+            ..Default::default()
+        };
+        let created = make_parents(trash_home(), &item.path, &item, &options).unwrap();
+        assert_eq!(created, vec![(dir.join("a"), None), (dir.join("a/b"), Some(0o40700))]);
+        assert!(dir.join("a/b").is_dir());
+        assert!(make_parents(trash_home(), &item.path, &item, &options).unwrap().is_empty());
+
+        let options = Options {
+            to: Some(dir.join("elsewhere")),
+            ..Default::default()
+        };
+        let err = make_parents(trash_home(), &destination(&item, &options), &item, &options).unwrap_err();
+        assert!(err.ends_with("restore with --parents"), "{}", err);
+    }
+
+    #[test]
+    fn parses_conflict_policies() {
+        for name in Conflict::NAMES {
+            assert!(Conflict::parse(name).is_some(), "{}", name);
+        }
+        assert_eq!(Conflict::parse("merge"), Some(Conflict::Merge));
+        assert_eq!(Conflict::parse("Merge"), None);
+        assert_eq!(Conflict::parse(""), None);
+    }
+
+    #[test]
+    fn free_names_count_up() {
+        let dir = scratch("free");
+        let dst = dir.join("notes.txt");
+        assert_eq!(free_name(&dst), dir.join("notes.txt.restored"));
+        fs::write(dir.join("notes.txt.restored"), "").unwrap();
+        assert_eq!(free_name(&dst), dir.join("notes.txt.restored.2"));
+        fs::write(dir.join("notes.txt.restored.2"), "").unwrap();
+        assert_eq!(free_name(&dst), dir.join("notes.txt.restored.3"));
+    }
+
+    #[test]
+    fn merging_keeps_what_is_in_the_way() {
+        let dir = scratch("merge");
+        let (from, into) = (dir.join("from"), dir.join("into"));
+        fs::create_dir_all(from.join("sub")).unwrap();
+        fs::create_dir_all(into.join("sub")).unwrap();
+        fs::write(from.join("new.txt"), "new").unwrap();
+        fs::write(from.join("sub/same.txt"), "trashed").unwrap();
+        fs::write(into.join("sub/same.txt"), "kept").unwrap();
+        let mut decisions = Vec::new();
+        merge_dir(&from, &into, &mut decisions).unwrap();
+        assert!(!from.exists());
+        assert_eq!(fs::read_to_string(into.join("new.txt")).unwrap(), "new");
+        assert_eq!(fs::read_to_string(into.join("sub/same.txt")).unwrap(), "kept");
+        assert_eq!(fs::read_to_string(into.join("sub/same.txt.restored")).unwrap(), "trashed");
+        assert_eq!(decisions.len(), 1);
+        assert!(decisions[0].contains("same.txt.restored"), "{}", decisions[0]);
+    }
+
+    #[test]
+    fn path_recreates_the_item_own_directories() {
+        let dir = scratch("path");
+        let item = record("20240101T000000-00000002", &dir.join("gone/project"));
+        fs::create_dir_all(item.stored().join("src")).unwrap();
+        fs::set_permissions(item.stored().join("src"), fs::Permissions::from_mode(0o750)).unwrap();
+        fs::set_permissions(item.stored(), fs::Permissions::from_mode(0o710)).unwrap();
+        let options = Options {
+            path: Some(PathBuf::from("src/main.rs")),
+            ..Default::default()
+        };
+        // `gone` is outside of the item
+        let dst = destination(&item, &options);
+        let err = make_parents(trash_home(), &dst, &item, &options).unwrap_err();
+        assert!(err.contains(&quote_path(&dir.join("gone"))), "{}", err);
+
+        fs::create_dir(dir.join("gone")).unwrap();
+        let created = make_parents(trash_home(), &dst, &item, &options).unwrap();
+        assert_eq!(
+            created,
+            vec![(dir.join("gone/project"), Some(0o40710)), (dir.join("gone/project/src"), Some(0o40750))]
+        );
+    }
+
+    #[test]
+    fn selects_the_newest_of_each_path() {
+        let dir = scratch("select");
+        let th = trash_home();
+        let mut saved = Vec::new();
+        for (n, (path, deleted_at)) in [("a", 100), ("a", 200), ("a/b", 150), ("c", 300)].into_iter().enumerate() {
+            let mut item = record(&format!("20240101T000000-select{:02}", n), &dir.join(path));
+            item.deleted_at = deleted_at;
+            info::save(th, &item).unwrap();
+            saved.push(item.id);
+        }
+        // which of the saved records were selected
+        let picked = |selected: Vec<TrashInfo>| {
+            selected
+                .iter()
+                .map(|r| saved.iter().position(|id| *id == r.id).unwrap())
+                .collect::<Vec<_>>()
+        };
+        let under = Some(dir.as_path());
+        assert_eq!(picked(select(under, None, None).unwrap()), [1, 2, 3]);
+        assert_eq!(picked(select(Some(&dir.join("a")), None, None).unwrap()), [1, 2]);
+        assert_eq!(picked(select(under, None, Some(150)).unwrap()), [0, 2]);
+        assert_eq!(picked(select(under, Some(200), Some(299)).unwrap()), [1]);
+        assert!(select(Some(&dir.join("ab")), None, None).unwrap().is_empty());
+        for id in saved {
+            info::remove(th, &id).unwrap();
+        }
+    }
+}
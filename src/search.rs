@@ -0,0 +1,199 @@
+use crate::browse::is_binary;
+use crate::display::{printable, quote, quote_path};
+use crate::error::AppError;
+use crate::info::{self, TrashInfo};
+use crate::type_char;
+use regex::{Regex, RegexBuilder};
+use std::{
+    env,
+    ffi::OsStr,
+    fs::{self, Metadata},
+    path::{Path, PathBuf},
+};
+
+/// Turn a shell glob into an anchored regex. `*` and `?` stay within one
+/// path component, `**` crosses them, `[...]` is a character class.
+pub fn glob_to_regex(glob: &str) -> String {
+    let mut re = String::from("^");
+    let mut chars = glob.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '*' if chars.peek() == Some(&'*') => {
+                chars.next();
+                re.push_str(".*");
+            }
+            '*' => re.push_str("[^/]*"),
+            '?' => re.push_str("[^/]"),
+            '[' => {
+                re.push('[');
+                if chars.peek() == Some(&'!') {
+                    chars.next();
+                    re.push('^');
+                }
+                for c in chars.by_ref() {
+                    if c == ']' {
+                        break;
+                    }
+                    if c == '\\' || c == '[' {
+                        re.push('\\');
+                    }
+                    re.push(c);
+                }
+                re.push(']');
+            }
+            c => re.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    re.push('$');
+    re
+}
+
+/// Call `visit` with every record, the path of each thing inside its item
+/// relative to the item (empty for the item itself), where that thing is in
+/// the trash can and its metadata. Symlinks are not followed and unreadable
+/// directories are passed over.
+pub fn walk(mut visit: impl FnMut(&TrashInfo, &Path, &Path, &Metadata)) -> Result<(), AppError> {
+    let th = PathBuf::from(env::var("th").unwrap());
+    for record in info::all(&th)? {
+        let stored = record.stored();
+        let Ok(md) = stored.symlink_metadata() else { continue };
+        visit(&record, Path::new(""), &stored, &md);
+        if md.is_dir() {
+            walk_dir(&record, &stored, Path::new(""), &mut visit);
+        }
+    }
+    Ok(())
+}
+
+fn walk_dir(record: &TrashInfo, dir: &Path, rel: &Path, visit: &mut impl FnMut(&TrashInfo, &Path, &Path, &Metadata)) {
+    let Ok(entries) = fs::read_dir(dir) else { return };
+    let mut children: Vec<PathBuf> = entries.filter_map(|e| e.ok().map(|e| e.path())).collect();
+    children.sort();
+    for child in children {
+        let Ok(md) = child.symlink_metadata() else { continue };
+        let child_rel = rel.join(child.file_name().unwrap());
+        visit(record, &child_rel, &child, &md);
+        if md.is_dir() {
+            walk_dir(record, &child, &child_rel, visit);
+        }
+    }
+}
+
+/// How `find` refers to a match: the item id, followed by the path inside
+/// the item for nested matches, in the form `cat` and `restore` take
+pub fn spec(record: &TrashInfo, rel: &Path) -> PathBuf {
+    if rel.as_os_str().is_empty() {
+        PathBuf::from(&record.id)
+    } else {
+        Path::new(&record.id).join(rel)
+    }
+}
+
+/// Where a match was before it was deleted
+pub fn original(record: &TrashInfo, rel: &Path) -> PathBuf {
+    if rel.as_os_str().is_empty() {
+        record.path.clone()
+    } else {
+        record.path.join(rel)
+    }
+}
+
+/// `rmrs find`: items and things inside them whose name, or with a `/` in
+/// the pattern whose original path, matches. `regex` patterns are searched
+/// for anywhere in the original path.
+pub fn find(pattern: &str, regex: bool) -> Result<(), AppError> {
+    let (re, whole_path) = if regex {
+        (Regex::new(pattern)?, true)
+    } else if pattern.contains('/') {
+        // a relative pattern may match the end of a path
+        let re = glob_to_regex(pattern);
+        let re = if pattern.starts_with('/') { re } else { format!("(^|/){}", &re[1..]) };
+        (Regex::new(&re)?, true)
+    } else {
+        (Regex::new(&glob_to_regex(pattern))?, false)
+    };
+    let mut found: usize = 0;
+    walk(|record, rel, _, md| {
+        let original = original(record, rel);
+        let subject = if whole_path {
+            original.to_string_lossy()
+        } else {
+            original.file_name().unwrap_or_default().to_string_lossy()
+        };
+        if re.is_match(&subject) {
+            found += 1;
+            println!(
+                "{} {} {}",
+                quote_path(&spec(record, rel)),
+                type_char(&md.file_type()),
+                quote_path(&original)
+            );
+        }
+    })?;
+    if found == 0 {
+        return Err(AppError {
+            code: -16,
+            message: format!("nothing in the trash matches {}", quote(OsStr::new(pattern))),
+        });
+    }
+    Ok(())
+}
+
+/// `rmrs grep`: lines matching `pattern` in every text file in the trash,
+/// inside trashed directories too, as `<item>[/<path>]:<line number>:<line>`
+pub fn grep(pattern: &str, ignore_case: bool) -> Result<(), AppError> {
+    let re = RegexBuilder::new(pattern).case_insensitive(ignore_case).build()?;
+    let mut found: usize = 0;
+    walk(|record, rel, stored, md| {
+        if !md.is_file() || is_binary(stored).unwrap_or(true) {
+            return;
+        }
+        let Ok(content) = fs::read(stored) else { return };
+        let content = content.strip_suffix(b"\n").unwrap_or(&content);
+        for (n, line) in content.split(|b| *b == b'\n').enumerate() {
+            let line = String::from_utf8_lossy(line);
+            if re.is_match(&line) {
+                found += 1;
+                println!("{}:{}:{}", quote_path(&spec(record, rel)), n + 1, printable(&line));
+            }
+        }
+    })?;
+    if found == 0 {
+        return Err(AppError {
+            code: -16,
+            message: format!("nothing in the trash contains {}", quote(OsStr::new(pattern))),
+        });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matches(glob: &str, text: &str) -> bool {
+        Regex::new(&glob_to_regex(glob)).unwrap().is_match(text)
+    }
+
+    #[test]
+    fn globs_become_anchored_regexes() {
+        assert_eq!(glob_to_regex("*.rs"), "^[^/]*\\.rs$");
+        assert_eq!(glob_to_regex("a?c"), "^a[^/]c$");
+        assert_eq!(glob_to_regex("**"), "^.*$");
+    }
+
+    #[test]
+    fn stars_and_classes() {
+        assert!(matches("*.rs", "main.rs"));
+        assert!(!matches("*.rs", "src/main.rs"));
+        assert!(!matches("*.rs", "main.rs.bak"));
+        assert!(matches("**/*.rs", "src/bin/main.rs"));
+        assert!(matches("file?.txt", "file1.txt"));
+        assert!(!matches("file?.txt", "file10.txt"));
+        assert!(matches("[ab]x", "bx"));
+        assert!(!matches("[!ab]x", "ax"));
+        assert!(matches("[!ab]x", "cx"));
+        assert!(matches("a+b(c)", "a+b(c)"));
+        assert!(!matches("a.b", "axb"));
+    }
+}
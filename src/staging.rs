@@ -0,0 +1,349 @@
+use crate::display::quote;
+use crate::error::AppError;
+use crate::info;
+use crate::journal::{Journal, Op};
+use crate::now_timestamp;
+use std::{
+    env,
+    ffi::{OsStr, OsString},
+    fs::{self, File},
+    io::Write,
+    os::unix::fs::{DirBuilderExt, MetadataExt},
+    path::{Path, PathBuf},
+};
+
+const PURGED_DIR: &str = "purged";
+
+/// Items taken out of the trash together by `clear` or `versions --keep`.
+/// They wait at `<trash home>/purged/<id>`, laid out like the trash home
+/// itself, until the grace period is over.
+pub struct Batch {
+    pub id: String,
+    pub dir: PathBuf,
+}
+
+impl Batch {
+    fn files(&self) -> PathBuf {
+        self.dir.join("files")
+    }
+
+    /// When the batch was purged, from its id
+    pub fn purged_at(&self) -> i64 {
+        let stamp = self.id.get(..15).map(|t| {
+            format!(
+                "{}-{}-{} {}:{}:{}",
+                &t[0..4],
+                &t[4..6],
+                &t[6..8],
+                &t[9..11],
+                &t[11..13],
+                &t[13..15]
+            )
+        });
+        match stamp.and_then(|s| info::parse_time(&s).ok()) {
+            Some(t) => t,
+            None => self.dir.symlink_metadata().map(|md| md.mtime()).unwrap_or(0),
+        }
+    }
+
+    /// When the batch will be deleted for good
+    pub fn expires_at(&self) -> i64 {
+        self.purged_at() + grace()
+    }
+
+    /// Names of the items in the batch, and of records whose item is gone
+    pub fn names(&self) -> Result<Vec<OsString>, AppError> {
+        let mut names: Vec<OsString> = info::ids(&self.dir)?.into_iter().map(OsString::from).collect();
+        if self.files().exists() {
+            for entry in fs::read_dir(self.files())? {
+                names.push(entry?.file_name());
+            }
+        }
+        names.sort();
+        names.dedup();
+        Ok(names)
+    }
+}
+
+/// Seconds for a grace period like `7d`, `12h`, `30m`, `90s` or `0`
+pub fn parse_grace(text: &str) -> Result<i64, AppError> {
+    let invalid = || AppError {
+        code: -22,
+        message: format!(
+            "can't read \"{}\" as a grace period, use e.g. \"7d\", \"12h\", \"30m\" or \"0\"",
+            text
+        ),
+    };
+    let text = text.trim();
+    if text == "0" {
+        return Ok(0);
+    }
+    let unit = match text.chars().last() {
+        Some('s') => 1,
+        Some('m') => 60,
+        Some('h') => 3600,
+        Some('d') => 86400,
+        _ => return Err(invalid()),
+    };
+    let n: i64 = text[..text.len() - 1].parse().map_err(|_| invalid())?;
+    if n < 0 {
+        return Err(invalid());
+    }
+    n.checked_mul(unit).ok_or_else(invalid)
+}
+
+/// The configured grace period in seconds
+pub fn grace() -> i64 {
+    env::var("gp").ok().and_then(|gp| gp.parse().ok()).unwrap_or(7 * 86400)
+}
+
+fn purged_dir() -> PathBuf {
+    PathBuf::from(env::var("th").unwrap()).join(PURGED_DIR)
+}
+
+/// Every batch still waiting, oldest first
+pub fn batches() -> Result<Vec<Batch>, AppError> {
+    let mut batches = Vec::new();
+    if !purged_dir().exists() {
+        return Ok(batches);
+    }
+    for entry in fs::read_dir(purged_dir())? {
+        let entry = entry?;
+        batches.push(Batch {
+            id: entry.file_name().to_string_lossy().into_owned(),
+            dir: entry.path(),
+        });
+    }
+    batches.sort_by(|a, b| a.id.cmp(&b.id));
+    Ok(batches)
+}
+
+/// The batch `name` was purged into, if it is still waiting
+pub fn holding(name: &str) -> Result<Option<Batch>, AppError> {
+    Ok(batches()?.into_iter().rev().find(|b| {
+        b.files().join(name).symlink_metadata().is_ok() || info::load(&b.dir, name).is_ok()
+    }))
+}
+
+/// A new, empty batch
+pub fn create() -> Result<Batch, AppError> {
+    let mut id = info::new_id(now_timestamp())?;
+    // the latest batch is the last by id, also within the same second
+    let last = batches()?.pop().map(|b| b.id).unwrap_or_default();
+    while id <= last {
+        id = info::new_id(now_timestamp())?;
+    }
+    let dir = purged_dir().join(&id);
+    fs::DirBuilder::new().recursive(true).mode(0o700).create(dir.join("files"))?;
+    Ok(Batch { id, dir })
+}
+
+/// The batch an id, or an unambiguous prefix of one, refers to, the latest
+/// one without an id
+pub fn resolve(query: Option<&str>) -> Result<Batch, AppError> {
+    let mut matched: Vec<Batch> = batches()?
+        .into_iter()
+        .filter(|b| query.is_none_or(|q| b.id.starts_with(q)))
+        .collect();
+    match (matched.len(), query) {
+        (0, None) => Err(AppError {
+            code: -16,
+            message: "nothing purged is waiting to be deleted".to_string(),
+        }),
+        (0, Some(q)) => Err(AppError {
+            code: -16,
+            message: format!("no purged batch {}", quote(OsStr::new(q))),
+        }),
+        (1, _) | (_, None) => Ok(matched.pop().unwrap()),
+        (n, Some(q)) => Err(AppError {
+            code: -16,
+            message: format!("{} is ambiguous, it matches {} purged batches", quote(OsStr::new(q)), n),
+        }),
+    }
+}
+
+/// Move `<trash can>/<name>` and its record into `batch`. The item goes
+/// first, so whatever is left of an interrupted move is settled by
+/// [`settle_stage`].
+pub fn stage(batch: &Batch, name: &OsStr, journal: &mut Journal) -> Result<(), AppError> {
+    let th = PathBuf::from(env::var("th").unwrap());
+    let tc = PathBuf::from(env::var("tc").unwrap());
+    let seq = journal.begin(&Op::Stage {
+        name: name.to_os_string(),
+        batch: batch.dir.clone(),
+    })?;
+    if tc.join(name).symlink_metadata().is_ok() {
+        fs::rename(tc.join(name), batch.files().join(name))?;
+    }
+    transfer_record(&th, &batch.dir, name)?;
+    journal.end(seq)?;
+    Ok(())
+}
+
+/// Finish an interrupted [`stage`] whose item was already moved
+pub fn settle_stage(name: &OsStr, batch: &Path) -> Result<(), AppError> {
+    let th = PathBuf::from(env::var("th").unwrap());
+    let tc = PathBuf::from(env::var("tc").unwrap());
+    if tc.join(name).symlink_metadata().is_err() {
+        transfer_record(&th, batch, name)?;
+    }
+    Ok(())
+}
+
+/// Move `name` and its record from `batch` back into the trash can, unless
+/// the trash already has something of that name
+fn unstage(batch: &Batch, name: &OsStr, journal: &mut Journal) -> Result<bool, AppError> {
+    let th = PathBuf::from(env::var("th").unwrap());
+    let tc = PathBuf::from(env::var("tc").unwrap());
+    let recorded = name.to_str().is_some_and(|id| info::load(&th, id).is_ok());
+    if tc.join(name).symlink_metadata().is_ok() || recorded {
+        return Ok(false);
+    }
+    let seq = journal.begin(&Op::Unstage {
+        name: name.to_os_string(),
+        batch: batch.dir.clone(),
+    })?;
+    if batch.files().join(name).symlink_metadata().is_ok() {
+        fs::rename(batch.files().join(name), tc.join(name))?;
+    }
+    transfer_record(&batch.dir, &th, name)?;
+    journal.end(seq)?;
+    Ok(true)
+}
+
+/// Finish an interrupted [`unstage`] whose item was already moved
+pub fn settle_unstage(name: &OsStr, batch: &Path) -> Result<(), AppError> {
+    let th = PathBuf::from(env::var("th").unwrap());
+    if batch.join("files").join(name).symlink_metadata().is_err() {
+        transfer_record(batch, &th, name)?;
+    }
+    Ok(())
+}
+
+/// Records are named after ids, so a name that isn't UTF-8 belongs to an
+/// item of an older version that has none
+fn transfer_record(from: &Path, to: &Path, name: &OsStr) -> Result<(), AppError> {
+    match name.to_str() {
+        Some(id) => info::transfer(from, to, id),
+        None => Ok(()),
+    }
+}
+
+/// Delete `batch` for good
+pub fn delete(batch: &Batch, mut log: &File, now: &str, journal: &mut Journal) -> Result<(), AppError> {
+    let user: String = env::var("USER").unwrap_or("default".to_string());
+    let count = batch.names()?.len();
+    let seq = journal.begin(&Op::Purge { path: batch.dir.clone() })?;
+    let removed = fs::remove_dir_all(&batch.dir);
+    journal.end(seq)?;
+    let info_log = match &removed {
+        Ok(_) => format!("{} {} deleted purged batch {} for good: {} items\n", now, &user, batch.id, count),
+        Err(e) => format!(
+            "{} {} tried to delete purged batch {} while an error occured: {}\n",
+            now, &user, batch.id, e
+        ),
+    };
+    log.write_all(info_log.as_bytes())?;
+    removed?;
+    Ok(())
+}
+
+/// Delete every batch whose grace period is over. A batch that can't be
+/// deleted is logged and left for the next run, it mustn't stop the command
+/// that happened to run first.
+pub fn expire(log: &File, now: &str, journal: &mut Journal) -> Result<(), AppError> {
+    let now_ts = now_timestamp();
+    for batch in batches()? {
+        if batch.expires_at() <= now_ts {
+            if let Err(e) = delete(&batch, log, now, journal) {
+                eprintln!(
+                    "could not delete expired batch {}: {}",
+                    quote(OsStr::new(&batch.id)),
+                    e.message
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+/// `rmrs unclear --list`: the batches still waiting, oldest first
+pub fn show() -> Result<(), AppError> {
+    let batches = batches()?;
+    if batches.is_empty() {
+        println!("nothing purged is waiting to be deleted");
+    }
+    for batch in batches {
+        println!(
+            "{} {:>5} items, purged {}, deleted for good after {}",
+            quote(OsStr::new(&batch.id)),
+            batch.names()?.len(),
+            info::local_time(batch.purged_at()),
+            info::local_time(batch.expires_at())
+        );
+    }
+    Ok(())
+}
+
+/// `rmrs unclear`: move everything in `batch` back into the trash
+pub fn unclear(batch: &Batch, mut log: &File, now: &str, journal: &mut Journal) -> Result<(), AppError> {
+    let user: String = env::var("USER").unwrap_or("default".to_string());
+    let mut back: usize = 0;
+    let mut left: usize = 0;
+    for name in batch.names()? {
+        if unstage(batch, &name, journal)? {
+            back += 1;
+        } else {
+            left += 1;
+            eprintln!("{} is in the trash already, left in the batch", quote(&name));
+        }
+    }
+    journal.commit()?;
+    let info_log = format!("{} {} brought back {} items of purged batch {}\n", now, &user, back, batch.id);
+    log.write_all(info_log.as_bytes())?;
+    println!("brought back {} items of purged batch {}", back, quote(OsStr::new(&batch.id)));
+    if left > 0 {
+        return Err(AppError {
+            code: -19,
+            message: format!("{} items are left in purged batch {}", left, batch.id),
+        });
+    }
+    fs::remove_dir_all(&batch.dir)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_grace_periods() {
+        assert_eq!(parse_grace("7d").unwrap(), 7 * 86400);
+        assert_eq!(parse_grace("12h").unwrap(), 12 * 3600);
+        assert_eq!(parse_grace("30m").unwrap(), 30 * 60);
+        assert_eq!(parse_grace(" 90s ").unwrap(), 90);
+        assert_eq!(parse_grace("0").unwrap(), 0);
+        assert_eq!(parse_grace("0d").unwrap(), 0);
+    }
+
+    #[test]
+    fn rejects_bad_grace_periods() {
+        for text in [
+            "",
+            "soon",
+            "7",
+            "d",
+            "-1d",
+            "1.5h",
+            "7 d",
+            "99999999999999999d",
+        ] {
+            assert_eq!(
+                parse_grace(text).map_err(|e| e.code).unwrap_err(),
+                -22,
+                "{:?}",
+                text
+            );
+        }
+    }
+}
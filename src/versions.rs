@@ -0,0 +1,105 @@
+use crate::browse::item_size;
+use crate::display::{escape_path, quote, quote_path};
+use crate::error::AppError;
+use crate::info::{self, TrashInfo};
+use crate::journal::Journal;
+use crate::staging::{self, Batch};
+use crate::{friendly_size, type_char};
+use std::{
+    ffi::OsStr,
+    env,
+    fs::File,
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+/// Every original path with the items deleted from it, newest first. With
+/// `path` only that one, otherwise only paths deleted more than once.
+pub fn groups(path: Option<&Path>) -> Result<Vec<(PathBuf, Vec<TrashInfo>)>, AppError> {
+    let th = PathBuf::from(env::var("th").unwrap());
+    let mut groups: Vec<(PathBuf, Vec<TrashInfo>)> = Vec::new();
+    // oldest first, so that pushing to the front leaves the newest first
+    for record in info::all(&th)? {
+        if path.is_some_and(|p| p != record.path) {
+            continue;
+        }
+        match groups.iter_mut().find(|(p, _)| *p == record.path) {
+            Some((_, versions)) => versions.insert(0, record),
+            None => groups.push((record.path.clone(), vec![record])),
+        }
+    }
+    if path.is_none() {
+        groups.retain(|(_, versions)| versions.len() > 1);
+    }
+    groups.sort_by(|a, b| a.0.cmp(&b.0));
+    Ok(groups)
+}
+
+/// `rmrs versions`: the items deleted from the same path, numbered from
+/// the newest, with deletion time, mode and size
+pub fn show(groups: &[(PathBuf, Vec<TrashInfo>)]) -> Result<(), AppError> {
+    for (path, versions) in groups {
+        println!("{}", quote_path(path));
+        for (n, record) in versions.iter().enumerate() {
+            let stored = record.stored();
+            let Ok(md) = stored.symlink_metadata() else { continue };
+            println!(
+                "  {:>2} {} {} {} ${:03o} {}",
+                n + 1,
+                quote(OsStr::new(&record.id)),
+                record.deleted_time(),
+                type_char(&md.file_type()),
+                record.mode % 512,
+                friendly_size(item_size(&stored, &md))
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Move all but the newest `keep` versions of each group into the purged
+/// `batch` and return how many went. Like `clear` this leaves `.last` alone,
+/// so that `-z` works again after `rmrs unclear`.
+pub fn prune(
+    groups: &[(PathBuf, Vec<TrashInfo>)],
+    keep: usize,
+    batch: &Batch,
+    mut log: &File,
+    now: &str,
+    journal: &mut Journal,
+) -> Result<usize, AppError> {
+    let user: String = env::var("USER").unwrap_or("default".to_string());
+    let mut pruned: usize = 0;
+    for (path, versions) in groups {
+        for record in versions.iter().skip(keep) {
+            let info_log = match staging::stage(batch, OsStr::new(&record.id), journal) {
+                Ok(_) => {
+                    pruned += 1;
+                    format!(
+                        "{} {} purged old version {} of \"{}\" as batch {}, keeping {}\n",
+                        now,
+                        &user,
+                        record.id,
+                        escape_path(path),
+                        batch.id,
+                        keep
+                    )
+                }
+                Err(e) => {
+                    eprintln!("could not purge {}: {}", quote(OsStr::new(&record.id)), e.message);
+                    format!(
+                        "{} {} tried to purge old version {} of \"{}\" while an error occured: {}\n",
+                        now,
+                        &user,
+                        record.id,
+                        escape_path(path),
+                        e.message
+                    )
+                }
+            };
+            log.write_all(info_log.as_bytes())?;
+        }
+    }
+    journal.commit()?;
+    Ok(pruned)
+}